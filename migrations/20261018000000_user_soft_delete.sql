@@ -0,0 +1,3 @@
+-- Marks soft-deleted users; only used if "user" is listed in `soft_delete` in .env.
+ALTER TABLE `user` ADD COLUMN `deleted_at` datetime DEFAULT NULL;
+CREATE INDEX `user_deleted_at` ON `user` (`deleted_at`);
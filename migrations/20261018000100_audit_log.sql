@@ -0,0 +1,14 @@
+-- Records every change made through the API, see `audit::record`.
+CREATE TABLE `audit_log` (
+  `id` bigint(20) unsigned NOT NULL AUTO_INCREMENT,
+  `table_name` varchar(64) NOT NULL,
+  `row_id` int(10) unsigned NOT NULL,
+  `action` varchar(16) NOT NULL,
+  `actor` varchar(64) NOT NULL,
+  `request_id` varchar(64) NOT NULL,
+  `before` longtext DEFAULT NULL,
+  `after` longtext DEFAULT NULL,
+  `created_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (`id`),
+  KEY `audit_log_row` (`table_name`, `row_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,10 @@
+-- Responses stored for requests with an Idempotency-Key, see `idempotency::save`.
+CREATE TABLE `idempotency_keys` (
+  `actor` varchar(64) NOT NULL,
+  `idempotency_key` varchar(255) NOT NULL,
+  `fingerprint` char(64) NOT NULL,
+  `response` longtext NOT NULL,
+  `created_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (`actor`, `idempotency_key`),
+  KEY `idempotency_keys_created_at` (`created_at`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
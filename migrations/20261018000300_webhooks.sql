@@ -0,0 +1,31 @@
+-- Webhook subscriptions registered by admins, see `webhooks::post_webhook`.
+CREATE TABLE `webhooks` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `url` varchar(2048) NOT NULL,
+  `secret` varchar(255) NOT NULL,
+  `table_name` varchar(64) DEFAULT NULL,
+  `actions` varchar(64) NOT NULL,
+  `created_by` varchar(64) NOT NULL,
+  `created_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (`id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- One row per event sent to a webhook, written in the same transaction as the change, see `delivery::run`.
+-- Deliveries that fail too many times are kept with the `dead` state until they are retried.
+CREATE TABLE `webhook_deliveries` (
+  `id` bigint(20) unsigned NOT NULL AUTO_INCREMENT,
+  `webhook_id` int(10) unsigned NOT NULL,
+  `action` varchar(16) NOT NULL,
+  `payload` longtext NOT NULL,
+  `state` varchar(16) NOT NULL DEFAULT 'pending',
+  `attempts` int(10) unsigned NOT NULL DEFAULT 0,
+  `response_status` smallint(5) unsigned DEFAULT NULL,
+  `last_error` text DEFAULT NULL,
+  `next_attempt_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  `created_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  `delivered_at` datetime DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `webhook_deliveries_due` (`state`, `next_attempt_at`),
+  KEY `webhook_deliveries_webhook` (`webhook_id`),
+  CONSTRAINT `webhook_deliveries_webhook_fk` FOREIGN KEY (`webhook_id`) REFERENCES `webhooks` (`id`) ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
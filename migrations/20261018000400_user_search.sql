@@ -0,0 +1,3 @@
+-- The full-text index used by `search::search_user`.
+-- It must cover exactly the searched columns: every varchar field, sorted, unless the `search` config lists others.
+ALTER TABLE `user` ADD FULLTEXT KEY `user_search` (`email`, `name`, `registered`);
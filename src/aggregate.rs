@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use sqlx::Row as SqlxRow;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use rustract::types::DataType;
+
+use crate::DB_DESIGN;
+use crate::ErrorType;
+use crate::AppError;
+use crate::Check;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::list::ListQuery;
+use crate::list::DEFAULT_LIMIT;
+use crate::list::MAX_LIMIT;
+use crate::list::parse_list_query;
+use crate::auth::Actor;
+use crate::auth::with_actor;
+
+/// The most columns a result can be grouped by.
+const MAX_GROUP_BY: usize = 5;
+
+/// The most metrics a request can ask for.
+const MAX_METRICS: usize = 20;
+
+/// An aggregate function of a metric.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<Function> {
+        match name {
+            "count" => Some(Function::Count),
+            "sum" => Some(Function::Sum),
+            "avg" => Some(Function::Avg),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the function can be used on a field of this type.
+    fn accepts(&self, datatype: &DataType) -> bool {
+        match self {
+            Function::Count => true,
+            Function::Sum | Function::Avg => matches!(datatype, DataType::Signed | DataType::Unsigned | DataType::Float),
+            Function::Min | Function::Max => !matches!(datatype, DataType::Byte | DataType::Set | DataType::Json),
+        }
+    }
+}
+
+/// A value computed for each group, such as `count` or `avg:id`.
+#[derive(Debug, Clone, PartialEq)]
+struct Metric {
+    /// The metric as it was asked for, which is also its key in the results.
+    name: String,
+    function: Function,
+    /// The field the function is applied to, or `None` to count rows.
+    field: Option<String>,
+}
+
+impl Metric {
+    /// Returns the SQL computing the metric, with the types MySQL returns turned into JSON-friendly ones.
+    fn sql(&self, datatype: Option<&DataType>) -> String {
+        let field = match &self.field {
+            Some(field) => field,
+            None => return "COUNT(*)".to_string(),
+        };
+        match self.function {
+            Function::Count => format!("COUNT(`{}`)", field),
+            // Integer sums are DECIMAL, which would be read as a string
+            Function::Sum if datatype != Some(&DataType::Float) => format!("CAST(SUM(`{}`) AS SIGNED)", field),
+            Function::Sum => format!("SUM(`{}`)", field),
+            // Averages of integers are DECIMAL too
+            Function::Avg => format!("CAST(AVG(`{}`) AS DOUBLE)", field),
+            Function::Min => format!("MIN(`{}`)", field),
+            Function::Max => format!("MAX(`{}`)", field),
+        }
+    }
+}
+
+/// A parsed aggregate request.
+pub(crate) struct AggregateQuery {
+    /// The filters and paging of the groups, parsed like a list query.
+    list: ListQuery,
+    group_by: Vec<String>,
+    metrics: Vec<Metric>,
+}
+
+/// The metrics of one group of rows.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct AggregateGroup {
+    /// The values of the `group_by` columns shared by the group's rows.
+    pub group: Row,
+    /// The metrics of the group, keyed as they were asked for.
+    pub metrics: Row,
+}
+
+// GET <domain>/user/_count
+/// A function that returns a warp route for counting users.
+///
+/// The same filters as `list_user` can be used, e.g. `?type=Admin` or `?filter=...`.
+pub(crate) fn count_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / "_count")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(count_extract)
+        .and_then(count_retrieve)
+        .and(with_format())
+        .and_then(count_success)
+}
+
+/// Checks the filters, which are the only parameters a count takes.
+async fn count_extract(query: HashMap<String, String>, actor: Option<Actor>) -> Result<ListQuery, warp::reject::Rejection> {
+    for key in ["fields", "limit", "offset"] {
+        if query.contains_key(key) {
+            Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("query parameter {} cannot be used when counting", key),
+                field: Some(key.to_string()),
+            })?
+        }
+    }
+    Ok(parse_list_query("user", query, &actor)?)
+}
+
+/// Counts the matching users with an SQL COUNT query.
+async fn count_retrieve(query: ListQuery) -> Result<i64, warp::reject::Rejection> {
+    let sql = format!("SELECT COUNT(*) FROM `user`{}", db::where_sql("user", &query.filters, query.include_deleted));
+    let mut select = sqlx::query(&sql);
+    for value in query.filters.into_iter().flat_map(|condition| condition.values) {
+        select = db::bind(select, value);
+    }
+
+    let mut conn = db::acquire().await?;
+    let row = select.fetch_one(&mut conn).await.map_err(AppError::from)?;
+    Ok(row.try_get::<i64, _>(0).map_err(AppError::from)?)
+}
+
+/// Replies with `{ "count": n }`.
+async fn count_success(count: i64, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(serde_json::json!({ "count": count })),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+// GET <domain>/user/_aggregate?group_by=type&metrics=count,avg:id
+/// A function that returns a warp route for computing metrics over groups of users.
+///
+/// `group_by` lists the fields to group by, and `metrics` what to compute for each group:
+/// `count`, or `count`, `sum`, `avg`, `min` or `max` of a field, written as `max:id`.
+/// Groups are sorted by their values and paged with `limit` and `offset`, and the same filters as `list_user` can be used.
+pub(crate) fn aggregate_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / "_aggregate")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(aggregate_extract)
+        .and_then(aggregate_retrieve)
+        .and(with_format())
+        .and_then(aggregate_success)
+}
+
+/// Checks the groups and metrics against the table's fields, and parses the rest like a list query.
+async fn aggregate_extract(mut query: HashMap<String, String>, actor: Option<Actor>) -> Result<AggregateQuery, warp::reject::Rejection> {
+    if query.contains_key("fields") {
+        Err(AppError {
+            err_type: ErrorType::BadRequest,
+            message: "query parameter fields cannot be used when aggregating, use group_by and metrics".to_string(),
+            field: Some("fields".to_string()),
+        })?
+    }
+    let group_by = parse_group_by("user", query.remove("group_by").as_deref())?;
+    let metrics = parse_metrics("user", query.remove("metrics").as_deref())?;
+    Ok(AggregateQuery {
+        list: parse_list_query("user", query, &actor)?,
+        group_by,
+        metrics,
+    })
+}
+
+/// Parses the `group_by` parameter, a comma-separated list of fields.
+fn parse_group_by(table: &str, value: Option<&str>) -> Result<Vec<String>, AppError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    let design = DB_DESIGN.tables.get(table).check()?;
+
+    let mut group_by: Vec<String> = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        match design.fields.get(name) {
+            Some(field) if !matches!(field.datatype, DataType::Byte | DataType::Json) => {},
+            Some(_) => return Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("field {} cannot be grouped by", name),
+                field: Some("group_by".to_string()),
+            }),
+            None => return Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("group_by should be a comma-separated list of fields of {}, but \"{}\" is not one", table, name),
+                field: Some("group_by".to_string()),
+            }),
+        }
+        if !group_by.iter().any(|field| field == name) {
+            group_by.push(name.to_string());
+        }
+    }
+
+    if group_by.len() > MAX_GROUP_BY {
+        return Err(AppError {
+            err_type: ErrorType::BadRequest,
+            message: format!("group_by can have at most {} fields", MAX_GROUP_BY),
+            field: Some("group_by".to_string()),
+        });
+    }
+    Ok(group_by)
+}
+
+/// Parses the `metrics` parameter, a comma-separated list such as `count,avg:id`, which defaults to `count`.
+fn parse_metrics(table: &str, value: Option<&str>) -> Result<Vec<Metric>, AppError> {
+    let design = DB_DESIGN.tables.get(table).check()?;
+    let metric_error = |message: String| AppError {
+        err_type: ErrorType::BadRequest,
+        message,
+        field: Some("metrics".to_string()),
+    };
+
+    let mut metrics: Vec<Metric> = Vec::new();
+    for name in value.unwrap_or("count").split(',').map(str::trim) {
+        let (function_name, field) = match name.split_once(':') {
+            Some((function_name, field)) => (function_name, Some(field)),
+            None => (name, None),
+        };
+        let function = Function::parse(function_name).ok_or_else(|| metric_error(format!(
+            "metric \"{}\" should be count, or count, sum, avg, min or max of a field, e.g. max:id", name
+        )))?;
+
+        match field {
+            Some(field) => {
+                let field_design = design.fields.get(field).ok_or_else(|| metric_error(format!(
+                    "metric \"{}\" uses {}, which is not a field of {}", name, field, table
+                )))?;
+                if !function.accepts(&field_design.datatype) {
+                    return Err(metric_error(format!("{} cannot be computed for field {}", function_name, field)));
+                }
+            },
+            None if function != Function::Count => return Err(metric_error(format!(
+                "metric \"{}\" needs a field, e.g. {}:id", name, function_name
+            ))),
+            None => {},
+        }
+
+        if !metrics.iter().any(|metric| metric.name == name) {
+            metrics.push(Metric { name: name.to_string(), function, field: field.map(str::to_string) });
+        }
+    }
+
+    if metrics.len() > MAX_METRICS {
+        return Err(metric_error(format!("metrics can have at most {} entries", MAX_METRICS)));
+    }
+    Ok(metrics)
+}
+
+/// Computes the metrics with an SQL GROUP BY query.
+async fn aggregate_retrieve(query: AggregateQuery) -> Result<Vec<AggregateGroup>, warp::reject::Rejection> {
+    let design = DB_DESIGN.tables.get("user").check()?;
+
+    // Columns are aliased by position, so that field and metric names cannot clash
+    let mut columns: Vec<String> = query.group_by
+        .iter()
+        .enumerate()
+        .map(|(index, field)| format!("`{}` AS `g{}`", field, index))
+        .collect();
+    for (index, metric) in query.metrics.iter().enumerate() {
+        let datatype = metric.field.as_ref().and_then(|field| design.fields.get(field)).map(|field| &field.datatype);
+        columns.push(format!("{} AS `m{}`", metric.sql(datatype), index));
+    }
+    let grouping = query.group_by.iter().map(|field| format!("`{}`", field)).collect::<Vec<String>>().join(", ");
+    let sql = format!(
+        "SELECT {} FROM `user`{}{} LIMIT ? OFFSET ?",
+        columns.join(", "),
+        db::where_sql("user", &query.list.filters, query.list.include_deleted),
+        if grouping.is_empty() { String::new() } else { format!(" GROUP BY {} ORDER BY {}", grouping, grouping) }
+    );
+
+    let mut select = sqlx::query(&sql);
+    for value in query.list.filters.into_iter().flat_map(|condition| condition.values) {
+        select = db::bind(select, value);
+    }
+    let mut conn = db::acquire().await?;
+    let rows = select
+        .bind(query.list.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT))
+        .bind(query.list.offset)
+        .fetch_all(&mut conn)
+        .await
+        .map_err(AppError::from)?;
+
+    let mut groups = Vec::new();
+    for row in rows.iter() {
+        let mut values = db::row_to_json(row)?;
+        let mut group = Row::new();
+        for (index, field) in query.group_by.iter().enumerate() {
+            group.insert(field.to_string(), values.remove(&format!("g{}", index)).check()?);
+        }
+        let mut metrics = Row::new();
+        for (index, metric) in query.metrics.iter().enumerate() {
+            metrics.insert(metric.name.to_string(), values.remove(&format!("m{}", index)).check()?);
+        }
+        groups.push(AggregateGroup { group, metrics });
+    }
+    Ok(groups)
+}
+
+/// Replies with the metrics of each group.
+async fn aggregate_success(groups: Vec<AggregateGroup>, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(groups),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str, function: Function, field: Option<&str>) -> Metric {
+        Metric { name: name.to_string(), function, field: field.map(str::to_string) }
+    }
+
+    fn metrics_error(value: &str) -> AppError {
+        parse_metrics("user", Some(value)).expect_err("the metrics should be invalid")
+    }
+
+    #[test]
+    fn group_by_is_a_list_of_fields() {
+        assert_eq!(parse_group_by("user", None).unwrap(), Vec::<String>::new());
+        assert_eq!(parse_group_by("user", Some("type, registered,type")).unwrap(), vec!["type", "registered"]);
+        assert_eq!(parse_group_by("user", Some("id,name,email,registered,type")).unwrap().len(), MAX_GROUP_BY);
+
+        let error = parse_group_by("user", Some("type,kind")).unwrap_err();
+        assert_eq!(error.message, "group_by should be a comma-separated list of fields of user, but \"kind\" is not one");
+        assert_eq!(error.field.as_deref(), Some("group_by"));
+        assert!(parse_group_by("user", Some("")).is_err());
+    }
+
+    #[test]
+    fn metrics_default_to_count() {
+        assert_eq!(parse_metrics("user", None).unwrap(), vec![metric("count", Function::Count, None)]);
+    }
+
+    #[test]
+    fn metrics_apply_functions_to_fields() {
+        assert_eq!(
+            parse_metrics("user", Some("count, avg:id,max:name,count,min:type,count:registered")).unwrap(),
+            vec![
+                metric("count", Function::Count, None),
+                metric("avg:id", Function::Avg, Some("id")),
+                metric("max:name", Function::Max, Some("name")),
+                metric("min:type", Function::Min, Some("type")),
+                metric("count:registered", Function::Count, Some("registered")),
+            ]
+        );
+    }
+
+    #[test]
+    fn metrics_are_checked() {
+        let error = metrics_error("median:id");
+        assert_eq!(error.message, "metric \"median:id\" should be count, or count, sum, avg, min or max of a field, e.g. max:id");
+        assert_eq!(error.field.as_deref(), Some("metrics"));
+        assert_eq!(metrics_error("sum").message, "metric \"sum\" needs a field, e.g. sum:id");
+        assert_eq!(metrics_error("sum:name").message, "sum cannot be computed for field name");
+        assert_eq!(metrics_error("avg:type").message, "avg cannot be computed for field type");
+        assert_eq!(metrics_error("max:age").message, "metric \"max:age\" uses age, which is not a field of user");
+    }
+
+    #[test]
+    fn functions_accept_field_types() {
+        assert!(Function::Sum.accepts(&DataType::Float));
+        assert!(!Function::Avg.accepts(&DataType::String));
+        assert!(Function::Min.accepts(&DataType::String));
+        assert!(!Function::Max.accepts(&DataType::Json));
+        assert!(Function::Count.accepts(&DataType::Byte));
+    }
+}
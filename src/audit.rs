@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use serde_json::Value;
+use sqlx::MySqlConnection;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::ErrorType;
+use crate::AppError;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::auth::Actor;
+use crate::auth::actor_name;
+use crate::auth::require_admin;
+use crate::auth::with_actor;
+use crate::changes;
+use crate::changes::Change;
+use crate::cache;
+use crate::delivery;
+use crate::webhooks;
+use crate::list::DEFAULT_LIMIT;
+use crate::list::MAX_LIMIT;
+use crate::list::parse_number;
+
+/// The table audit entries are written to, created by a migration.
+const AUDIT_TABLE: &str = "audit_log";
+
+/// The header a client or proxy can use to name a request in the audit log.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Who made a request, recorded with each change it makes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditContext {
+    pub actor: Option<Actor>,
+    /// The `X-Request-Id` header, or a generated id if it was not sent.
+    pub request_id: String,
+    /// The changes recorded so far, which are sent to subscribers once they are committed.
+    staged: Arc<Mutex<Vec<Change>>>,
+}
+
+impl AuditContext {
+    /// Sends the recorded changes to subscribers (see `changes::publish`), wakes the webhook worker,
+    /// and clears the response cache.
+    ///
+    /// This should be called after the transaction they were recorded in has been committed.
+    pub(crate) fn publish(&self) {
+        let staged = self.take_staged();
+        if !staged.is_empty() {
+            cache::invalidate();
+            delivery::wake();
+        }
+        changes::publish(staged);
+    }
+
+    /// Forgets the recorded changes, after the transaction they were recorded in was rolled back.
+    pub(crate) fn discard(&self) {
+        self.take_staged();
+    }
+
+    /// Removes and returns the recorded changes.
+    fn take_staged(&self) -> Vec<Change> {
+        self.staged
+            .lock()
+            .map(|mut staged| std::mem::take(&mut *staged))
+            .unwrap_or_default()
+    }
+}
+
+/// The kinds of change recorded in the audit log.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Action {
+    Create,
+    Update,
+    Delete,
+    Restore,
+}
+
+impl Action {
+    /// Every kind of change, in the order they are documented.
+    pub(crate) const ALL: [Action; 4] = [Action::Create, Action::Update, Action::Delete, Action::Restore];
+
+    /// The name stored in the audit log.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Delete => "delete",
+            Action::Restore => "restore",
+        }
+    }
+}
+
+/// Extracts the actor and request id for the audit log.
+pub(crate) fn with_audit() -> impl Filter<Extract = (AuditContext,), Error = Rejection> + Clone {
+    with_actor()
+        .and(warp::header::optional::<String>(REQUEST_ID_HEADER))
+        .map(|actor: Option<Actor>, request_id: Option<String>| AuditContext {
+            actor,
+            request_id: request_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            staged: Arc::new(Mutex::new(Vec::new())),
+        })
+}
+
+/// Records a change to a row, with its values before and after the change.
+///
+/// This should use the same transaction as the change, so that the entry is only kept if the change is.
+/// The change is also queued for webhooks (see `webhooks::enqueue`), and staged to be sent to subscribers by `AuditContext::publish`.
+pub(crate) async fn record(
+    conn: &mut MySqlConnection,
+    context: &AuditContext,
+    table: &str,
+    id: u32,
+    action: Action,
+    before: Option<&Row>,
+    after: Option<&Row>,
+) -> Result<(), AppError> {
+    let sql = format!(
+        "INSERT INTO `{}` (`table_name`, `row_id`, `action`, `actor`, `request_id`, `before`, `after`) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        AUDIT_TABLE
+    );
+    sqlx::query(&sql)
+        .bind(table)
+        .bind(id)
+        .bind(action.name())
+        .bind(actor_name(&context.actor))
+        .bind(&context.request_id)
+        .bind(before.map(serde_json::to_string).transpose()?)
+        .bind(after.map(serde_json::to_string).transpose()?)
+        .execute(&mut *conn)
+        .await?;
+    webhooks::enqueue(conn, context, table, id, action, before, after).await?;
+
+    if let Ok(mut staged) = context.staged.lock() {
+        staged.push(Change::new(table, id, action.name(), after.cloned()));
+    }
+    Ok(())
+}
+
+// GET <domain>/audit?table=user&id=#
+/// A function that returns a warp route for reading the audit log, newest first.
+///
+/// Only admins may read the log. It can be narrowed with `table` and `id`, and paged with `limit` and `offset`.
+pub(crate) fn get_audit() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("audit")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(audit_retrieve)
+        .and(with_format())
+        .and_then(audit_success)
+}
+
+/// Uses the query to make an SQL SELECT query on the audit log.
+async fn audit_retrieve(query: HashMap<String, String>, actor: Option<Actor>) -> Result<Vec<Row>, warp::reject::Rejection> {
+    require_admin(&actor)?;
+
+    let mut filters: Vec<(&str, String)> = Vec::new();
+    let mut limit = DEFAULT_LIMIT;
+    let mut offset = 0;
+    for (key, value) in query {
+        match key.as_str() {
+            "table" => filters.push(("table_name", value)),
+            "id" => filters.push(("row_id", parse_number(&key, &value)?.to_string())),
+            "limit" => limit = parse_number(&key, &value)?.min(MAX_LIMIT),
+            "offset" => offset = parse_number(&key, &value)?,
+            _ => Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("unknown query parameter {}: expected table, id, limit or offset", key),
+                field: Some(key),
+            })?,
+        }
+    }
+
+    // Sorted so that the generated SQL is the same for the same filters
+    filters.sort();
+    let conditions: Vec<String> = filters.iter().map(|(key, _)| format!("`{}` = ?", key)).collect();
+    let sql = format!(
+        "SELECT * FROM `{}`{} ORDER BY `id` DESC LIMIT ? OFFSET ?",
+        AUDIT_TABLE,
+        if conditions.is_empty() { String::new() } else { format!(" WHERE {}", conditions.join(" AND ")) }
+    );
+    let mut sql_query = sqlx::query(&sql);
+    for (_, value) in filters {
+        sql_query = sql_query.bind(value);
+    }
+
+    let mut conn = db::acquire().await?;
+    let rows = sql_query
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&mut conn)
+        .await
+        .map_err(AppError::from)?;
+
+    let mut entries = Vec::new();
+    for row in rows.iter() {
+        let mut entry = db::row_to_json(row)?;
+        // The row values are stored as JSON text
+        for key in ["before", "after"] {
+            if let Some(Value::String(text)) = entry.get(key) {
+                let value = serde_json::from_str(text).map_err(AppError::from)?;
+                entry.insert(key.to_string(), value);
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Replies with the audit entries.
+async fn audit_success(entries: Vec<Row>, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(entries),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
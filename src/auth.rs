@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use warp::Rejection;
+use warp::Filter;
+use warp::hyper::header;
+
+use crate::env;
+use crate::AppError;
+use crate::ErrorType;
+
+/// The roles a client can have, matching the `type` column of the user table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub(crate) enum Role {
+    Admin,
+    Mod,
+    Basic,
+}
+
+/// An API token listed in the config.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct TokenConfig {
+    /// The name recorded for requests made with this token.
+    pub name: String,
+    pub role: Role,
+}
+
+/// The client making a request, identified by its bearer token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Actor {
+    pub name: String,
+    pub role: Role,
+}
+
+impl Actor {
+    /// Returns whether this client is an admin.
+    pub(crate) fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+}
+
+/// Extracts the client from the `Authorization: Bearer <token>` header.
+///
+/// Requests without the header are anonymous, but unknown tokens are rejected with `401 Unauthorized`.
+pub(crate) fn with_actor() -> impl Filter<Extract = (Option<Actor>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(header::AUTHORIZATION.as_str())
+        .and_then(|authorization: Option<String>| async move {
+            match authorization {
+                Some(authorization) => find_actor(&authorization).map(Some).map_err(AppError::into_warp),
+                None => Ok::<_, Rejection>(None),
+            }
+        })
+}
+
+/// Looks up the client for an `Authorization` header value.
+fn find_actor(authorization: &str) -> Result<Actor, AppError> {
+    let token = authorization
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| AppError::new(
+            ErrorType::Unauthorized,
+            "Unauthorized: expected an Authorization header of the form \"Bearer <token>\"".to_string()
+        ))?;
+
+    env()
+        .and_then(|env| env.tokens.get(token.trim()))
+        .map(|config| Actor {
+            name: config.name.clone(),
+            role: config.role,
+        })
+        .ok_or_else(|| AppError::new(
+            ErrorType::Unauthorized,
+            "Unauthorized: the token is not valid".to_string()
+        ))
+}
+
+/// Errors with `401 Unauthorized` or `403 Forbidden` unless the client is an admin.
+pub(crate) fn require_admin(actor: &Option<Actor>) -> Result<(), AppError> {
+    match actor {
+        Some(actor) if actor.is_admin() => Ok(()),
+        Some(_) => Err(AppError::new(
+            ErrorType::Forbidden,
+            "Forbidden: this action is only available to admins".to_string()
+        )),
+        None => Err(AppError::new(
+            ErrorType::Unauthorized,
+            "Unauthorized: this action requires an admin token".to_string()
+        )),
+    }
+}
+
+/// Extracts the `?include_deleted=true` flag, which only admins may set.
+///
+/// Other query parameters are ignored.
+pub(crate) fn with_include_deleted() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and(with_actor())
+        .and_then(|query: HashMap<String, String>, actor: Option<Actor>| async move {
+            include_deleted(query.get("include_deleted").map(String::as_str), &actor)
+                .map_err(AppError::into_warp)
+        })
+}
+
+/// Parses an `include_deleted` query value, checking that the client is an admin if it is set.
+pub(crate) fn include_deleted(value: Option<&str>, actor: &Option<Actor>) -> Result<bool, AppError> {
+    let include = match value {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(other) => return Err(AppError {
+            err_type: ErrorType::BadRequest,
+            message: format!("include_deleted must be true or false, not \"{}\"", other),
+            field: Some("include_deleted".to_string()),
+        }),
+    };
+
+    if include {
+        require_admin(actor)?;
+    }
+    Ok(include)
+}
+
+/// Returns the name to record for a request, or `anonymous` if no token was sent.
+pub(crate) fn actor_name(actor: &Option<Actor>) -> String {
+    actor
+        .as_ref()
+        .map_or_else(|| "anonymous".to_string(), |actor| actor.name.clone())
+}
@@ -0,0 +1,359 @@
+use serde_json::Value;
+use sqlx::MySqlConnection;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::ErrorType;
+use crate::AppError;
+use crate::debug_mode;
+use crate::db;
+use crate::db::Row;
+use crate::post::create_user;
+use crate::post::post_extract;
+use crate::patch::patch_extract;
+use crate::patch::update_user;
+use crate::delete::remove_user;
+use crate::routes::respond;
+use crate::routes::with_body_limit;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
+use crate::idempotency;
+use crate::idempotency::with_idempotency_key;
+
+/// The maximum size of a bulk request body, in bytes.
+pub(crate) const BULK_MAX_BODY_SIZE: u64 = 1024 * 1024 * 4;
+
+/// The maximum number of operations in one bulk request.
+pub(crate) const BULK_MAX_OPERATIONS: usize = 1000;
+
+/// How the operations of a bulk request are applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BulkMode {
+    /// Every operation runs in one transaction, which is rolled back if any of them fail.
+    #[default]
+    Atomic,
+    /// Every operation runs in its own transaction, so failures do not affect the others.
+    BestEffort,
+}
+
+/// The query of a bulk request, e.g. `?mode=best_effort`.
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct BulkQuery {
+    #[serde(default)]
+    pub mode: BulkMode,
+}
+
+/// One operation of a bulk request, e.g. `{ "op": "update", "id": 1, "body": { "name": "Ann" } }`.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum Operation {
+    /// Validated like a POST body.
+    Create { body: Value },
+    /// Validated like a PATCH body, with an optional `ETag` to check like `If-Match`.
+    Update { id: u32, body: Value, if_match: Option<String> },
+    Delete { id: u32, if_match: Option<String> },
+}
+
+/// The outcome of one operation, in the same order as the request.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct BulkResult {
+    pub index: usize,
+    /// The status code the operation would have had as a single request.
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<Row>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+/// The reply to a bulk request.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct BulkResponse {
+    pub mode: BulkMode,
+    /// Whether any changes were kept. In atomic mode, this is false if any operation failed.
+    pub committed: bool,
+    pub results: Vec<BulkResult>,
+}
+
+// POST <domain>/user/_bulk
+/// A function that returns a warp route for creating, updating and deleting many users at once.
+///
+/// The body is an array of operations, which are applied in order.
+/// By default they are all-or-nothing; `?mode=best_effort` applies every operation that succeeds.
+pub(crate) fn bulk_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / "_bulk")
+        .and(warp::post())
+        .and(check_accept())
+        .and(warp::query::<BulkQuery>())
+        .and(with_body_limit(BULK_MAX_BODY_SIZE))
+        .and_then(bulk_extract)
+        .and(with_audit())
+        .and(with_idempotency_key())
+        .and_then(bulk_insert)
+        .and(with_format())
+        .and_then(bulk_success)
+}
+
+/// Checks that the body is an array of at most `BULK_MAX_OPERATIONS` operations.
+///
+/// Each operation is validated when it runs, so that its error can be reported with its index.
+async fn bulk_extract(query: BulkQuery, body: Value) -> Result<(BulkMode, Vec<Value>), warp::reject::Rejection> {
+    match body {
+        Value::Array(operations) if operations.len() <= BULK_MAX_OPERATIONS => Ok((query.mode, operations)),
+        Value::Array(operations) => Err(AppError::new(
+            ErrorType::BadRequest,
+            format!("a bulk request can have at most {} operations, but {} were sent", BULK_MAX_OPERATIONS, operations.len())
+        ))?,
+        _ => Err(AppError::new(
+            ErrorType::BadRequest,
+            "failed to parse bulk request (err: body should be an array of operations)".to_string()
+        ))?,
+    }
+}
+
+/// Runs the operations, in one transaction or one each depending on the mode.
+///
+/// If an `Idempotency-Key` is sent, requests that changed anything are stored with it and replayed for retries.
+async fn bulk_insert(req: (BulkMode, Vec<Value>), context: AuditContext, key: Option<String>) -> Result<(StatusCode, BulkResponse), warp::reject::Rejection> {
+    let (mode, operations) = req;
+    let fingerprint = idempotency::fingerprint("POST /user/_bulk", &(mode, &operations))?;
+    if let Some(key) = &key {
+        let mut conn = db::acquire().await?;
+        if let Some(response) = idempotency::find(&mut conn, &context, key, &fingerprint).await? {
+            return Ok((StatusCode::OK, response));
+        }
+    }
+
+    let mut results = Vec::with_capacity(operations.len());
+
+    match mode {
+        BulkMode::Atomic => {
+            let mut tx = db::begin().await?;
+            let mut failed = None;
+            for (index, operation) in operations.into_iter().enumerate() {
+                if failed.is_some() {
+                    results.push(not_applied(index, &failed));
+                    continue;
+                }
+                let result = run_operation(&mut tx, operation, &context).await;
+                if result.is_err() {
+                    failed = Some(index);
+                }
+                results.push(bulk_result(index, result));
+            }
+
+            match failed {
+                None => {
+                    let response = BulkResponse { mode, committed: true, results };
+                    if let Some(key) = &key {
+                        idempotency::save(&mut tx, &context, key, &fingerprint, &response).await?;
+                    }
+                    tx.commit().await.map_err(AppError::from)?;
+                    context.publish();
+                    Ok((StatusCode::OK, response))
+                },
+                Some(index) => {
+                    tx.rollback().await.map_err(AppError::from)?;
+                    context.discard();
+                    let status = undo_results(&mut results, index);
+                    Ok((status, BulkResponse { mode, committed: false, results }))
+                },
+            }
+        },
+        BulkMode::BestEffort => {
+            let mut committed = false;
+            for (index, operation) in operations.into_iter().enumerate() {
+                // Failing to begin or commit only fails this operation, since earlier ones may have been committed
+                let result = run_alone(operation, &context).await;
+                if result.is_ok() {
+                    context.publish();
+                    committed = true;
+                } else {
+                    context.discard();
+                }
+                results.push(bulk_result(index, result));
+            }
+
+            // The operations were committed one by one, so the response is stored on its own.
+            // Failing to store it is only logged, because the committed changes are reported either way.
+            let response = BulkResponse { mode, committed, results };
+            if let (Some(key), true) = (&key, committed) {
+                if let Err(err) = save_response(&context, key, &fingerprint, &response).await {
+                    let id = uuid::Uuid::new_v4().to_string();
+                    eprintln!("error {} (idempotency): failed to store bulk response: {}", id, err.message);
+                }
+            }
+            Ok((StatusCode::OK, response))
+        },
+    }
+}
+
+/// Runs one operation in its own transaction, which is committed if the operation succeeds.
+async fn run_alone(operation: Value, context: &AuditContext) -> Result<(StatusCode, Row), Rejection> {
+    let mut tx = db::begin().await?;
+    let result = run_operation(&mut tx, operation, context).await?;
+    tx.commit().await.map_err(AppError::from)?;
+    Ok(result)
+}
+
+/// Stores the response of a best-effort request with its `Idempotency-Key`.
+async fn save_response(context: &AuditContext, key: &str, fingerprint: &str, response: &BulkResponse) -> Result<(), AppError> {
+    let mut conn = db::acquire().await?;
+    idempotency::save(&mut conn, context, key, fingerprint, response).await
+}
+
+/// Validates and applies one operation with the same logic as the single-row routes.
+async fn run_operation(conn: &mut MySqlConnection, operation: Value, context: &AuditContext) -> Result<(StatusCode, Row), Rejection> {
+    let operation: Operation = serde_json::from_value(operation).map_err(|err| AppError::new(
+        ErrorType::BadRequest,
+        format!("invalid operation: {}", err)
+    ))?;
+
+    match operation {
+        Operation::Create { body } => {
+            let req = post_extract(body).await?;
+            Ok((StatusCode::CREATED, create_user(conn, req, context).await?))
+        },
+        Operation::Update { id, body, if_match } => {
+            let (id, req) = patch_extract(id, body).await?;
+            let values = db::sql_values("user", req)?;
+            Ok((StatusCode::OK, update_user(conn, id, values, &if_match, context).await?))
+        },
+        Operation::Delete { id, if_match } => {
+            Ok((StatusCode::OK, remove_user(conn, id, &if_match, context).await?))
+        },
+    }
+}
+
+/// Describes the outcome of an operation.
+///
+/// Like `handle_rejection`, server errors are logged and only described to the client in debug mode.
+fn bulk_result(index: usize, result: Result<(StatusCode, Row), Rejection>) -> BulkResult {
+    match result {
+        Ok((status, row)) => BulkResult {
+            index,
+            status: status.as_u16(),
+            row: Some(row),
+            error: None,
+            field: None,
+        },
+        Err(rejection) => {
+            let (status, mut message, mut field) = match rejection.find::<AppError>() {
+                Some(err) => (err.to_http_status(), err.message.clone(), err.field.clone()),
+                None => (StatusCode::INTERNAL_SERVER_ERROR, format!("Unhandled rejection: {:?}", rejection), None),
+            };
+            if status.is_server_error() {
+                let id = uuid::Uuid::new_v4().to_string();
+                eprintln!("error {} ({}): bulk operation #{}: {}", id, status, index, message);
+                if !debug_mode() {
+                    message = format!("Internal Server Error (error id {})", id);
+                    field = None;
+                }
+            }
+            BulkResult {
+                index,
+                status: status.as_u16(),
+                row: None,
+                error: Some(message),
+                field,
+            }
+        },
+    }
+}
+
+/// Marks the operations before the failed one as undone, since their rows were rolled back.
+///
+/// Returns the status of the failed operation, which is used for the whole response.
+fn undo_results(results: &mut [BulkResult], failed: usize) -> StatusCode {
+    let status = StatusCode::from_u16(results[failed].status).unwrap_or(StatusCode::BAD_REQUEST);
+    for result in results.iter_mut().take(failed) {
+        *result = not_applied(result.index, &Some(failed));
+    }
+    status
+}
+
+/// Describes an operation that was skipped or undone because another one failed.
+fn not_applied(index: usize, failed: &Option<usize>) -> BulkResult {
+    BulkResult {
+        index,
+        status: StatusCode::FAILED_DEPENDENCY.as_u16(),
+        row: None,
+        error: Some(format!("not applied, because operation #{} failed", failed.unwrap_or_default())),
+        field: None,
+    }
+}
+
+/// Replies with the result of each operation.
+async fn bulk_success(req: (StatusCode, BulkResponse), format: Format) -> Result<impl Reply, Rejection> {
+    let (status, response) = req;
+    respond(
+        Ok(response),
+        status,
+        format
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(index: usize) -> BulkResult {
+        let mut row = Row::new();
+        row.insert("id".to_string(), Value::from(index + 1));
+        bulk_result(index, Ok((StatusCode::CREATED, row)))
+    }
+
+    #[test]
+    fn atomic_failures_undo_the_earlier_operations() {
+        let conflict = AppError::new(ErrorType::Conflict, "duplicate email".to_string());
+        let mut results = vec![applied(0), applied(1), bulk_result(2, Err(conflict.into_warp()))];
+        results.push(not_applied(3, &Some(2)));
+
+        let status = undo_results(&mut results, 2);
+        assert_eq!(status, StatusCode::CONFLICT);
+        for index in [0, 1, 3] {
+            assert_eq!(results[index].status, 424);
+            assert!(results[index].row.is_none());
+            assert_eq!(results[index].error.as_deref(), Some("not applied, because operation #2 failed"));
+        }
+        assert_eq!(results[2].status, 409);
+        assert_eq!(results[2].error.as_deref(), Some("duplicate email"));
+    }
+
+    #[test]
+    fn results_describe_rows_and_errors() {
+        let value = serde_json::to_value(applied(0)).unwrap();
+        assert_eq!(value, serde_json::json!({ "index": 0, "status": 201, "row": { "id": 1 } }));
+
+        let mut invalid = AppError::new(ErrorType::BadRequest, "name is too long".to_string());
+        invalid.field = Some("name".to_string());
+        let value = serde_json::to_value(bulk_result(1, Err(invalid.into_warp()))).unwrap();
+        assert_eq!(value, serde_json::json!({ "index": 1, "status": 400, "error": "name is too long", "field": "name" }));
+    }
+
+    #[tokio::test]
+    async fn best_effort_reports_every_operation_when_transactions_fail() {
+        // Without a database, beginning each transaction fails, which is reported per operation
+        let operations = vec![
+            serde_json::json!({ "op": "delete", "id": 1 }),
+            serde_json::json!({ "op": "delete", "id": 2 }),
+        ];
+        let (status, response) = bulk_insert((BulkMode::BestEffort, operations), AuditContext::default(), None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(!response.committed);
+        assert_eq!(response.results.len(), 2);
+        for (index, result) in response.results.iter().enumerate() {
+            assert_eq!(result.index, index);
+            assert_eq!(result.status, 500);
+            assert!(result.row.is_none());
+        }
+    }
+}
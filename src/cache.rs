@@ -0,0 +1,299 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use serde_json::Value;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::env;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::auth::Actor;
+use crate::auth::require_admin;
+use crate::auth::with_actor;
+
+// The cache is created from the config when first used, and shared by every route.
+lazy_static! {
+    static ref CACHE: Option<Mutex<ResponseCache>> = NonZeroUsize::new(env().map_or(0, |env| env.cache_capacity))
+        .map(|capacity| Mutex::new(ResponseCache::new(capacity)));
+    static ref STATS: Stats = Stats::default();
+}
+
+/// What a cached read is keyed by: the same request from a client with the same role gets the same reply.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    path: String,
+    /// The query string, with its parameters sorted.
+    query: String,
+    /// The role of the client, which decides e.g. whether soft-deleted rows can be read.
+    scope: String,
+}
+
+/// A cached result, with the time it was read from the database.
+struct Entry {
+    value: Value,
+    stored_at: Instant,
+}
+
+/// The cached results, and a count of the invalidations so far.
+struct ResponseCache {
+    entries: LruCache<CacheKey, Entry>,
+    /// Incremented on every invalidation, so that reads that started before it are not stored.
+    generation: u64,
+}
+
+impl ResponseCache {
+    fn new(capacity: NonZeroUsize) -> Self {
+        ResponseCache {
+            entries: LruCache::new(capacity),
+            generation: 0,
+        }
+    }
+
+    /// Returns a result stored less than `ttl` ago, dropping it if it has expired. See `lookup`.
+    fn lookup(&mut self, key: &CacheKey, ttl: Duration, stats: &Stats) -> Option<Value> {
+        let fresh = self.entries.get(key).map(|entry| entry.stored_at.elapsed() < ttl);
+        match fresh {
+            Some(true) => {
+                stats.hits.fetch_add(1, Ordering::Relaxed);
+                self.entries.get(key).map(|entry| entry.value.clone())
+            },
+            Some(false) => {
+                self.entries.pop(key);
+                stats.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+            None => {
+                stats.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+        }
+    }
+
+    /// Stores a result if no invalidation happened since `generation`. See `store`.
+    ///
+    /// Replacing the result of a key is not an eviction, only dropping another key to make room is.
+    fn store(&mut self, key: CacheKey, generation: u64, value: Value, stats: &Stats) {
+        if self.generation != generation {
+            return;
+        }
+        let replaced = self.entries.contains(&key);
+        if self.entries.push(key, Entry { value, stored_at: Instant::now() }).is_some() && !replaced {
+            stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops every result and starts a new generation. See `invalidate`.
+    fn invalidate(&mut self, stats: &Stats) {
+        self.entries.clear();
+        self.generation += 1;
+        stats.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The counters reported by `cache_stats`.
+#[derive(Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Entries dropped to make room for newer ones.
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+/// The body of the cache stats route.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct CacheReport {
+    pub enabled: bool,
+    pub capacity: usize,
+    pub ttl_seconds: u64,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// The share of lookups that were hits, or 0 if there were none.
+    pub hit_ratio: f64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+/// Returns how long results are cached for.
+fn ttl() -> Duration {
+    Duration::from_secs(env().map_or(30, |env| env.cache_ttl_seconds))
+}
+
+/// Locks the cache, or returns `None` if caching is disabled.
+fn cache() -> Option<MutexGuard<'static, ResponseCache>> {
+    CACHE.as_ref().map(|cache| cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+}
+
+/// Extracts the cache key of a read: its path, sorted query, and the client's role.
+///
+/// Unknown tokens are rejected with `401 Unauthorized`, like `with_actor`.
+pub(crate) fn with_cache_key() -> impl Filter<Extract = (CacheKey,), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(with_actor())
+        .map(|path: warp::path::FullPath, query: String, actor: Option<Actor>| cache_key(path.as_str(), &query, actor))
+}
+
+/// Builds the cache key of a read from its path, raw query string and client.
+fn cache_key(path: &str, query: &str, actor: Option<Actor>) -> CacheKey {
+    let mut parameters: Vec<&str> = query.split('&').filter(|parameter| !parameter.is_empty()).collect();
+    parameters.sort_unstable();
+    CacheKey {
+        path: path.to_string(),
+        query: parameters.join("&"),
+        scope: actor.map_or("anonymous".to_string(), |actor| format!("{:?}", actor.role)),
+    }
+}
+
+/// Returns the current generation, which should be read before the database so that it can be passed to `store`.
+///
+/// Returns `None` if caching is disabled.
+pub(crate) fn generation() -> Option<u64> {
+    cache().map(|cache| cache.generation)
+}
+
+/// Returns a cached result that has not expired, counting the lookup as a hit or a miss.
+pub(crate) fn lookup(key: &CacheKey) -> Option<Value> {
+    cache()?.lookup(key, ttl(), &STATS)
+}
+
+/// Caches a result read from the database, unless the cache was invalidated since `generation` was read.
+pub(crate) fn store(key: CacheKey, generation: Option<u64>, value: Value) {
+    if let (Some(mut cache), Some(generation)) = (cache(), generation) {
+        cache.store(key, generation, value, &STATS);
+    }
+}
+
+/// Drops every cached result. This is called once a change has been committed, see `AuditContext::publish`.
+///
+/// The whole cache is cleared, since a row can appear in lists and in the embedded rows of others.
+/// Rows removed by `cli purge` do not go through this, so admins can still be served them until the TTL expires.
+pub(crate) fn invalidate() {
+    if let Some(mut cache) = cache() {
+        cache.invalidate(&STATS);
+    }
+}
+
+// GET <domain>/cache
+/// A function that returns a warp route for reading the response cache's hit counts.
+///
+/// Only admins may read the stats. The counters start at zero when the server starts.
+pub(crate) fn cache_stats() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("cache")
+        .and(warp::get())
+        .and(with_actor())
+        .and_then(cache_retrieve)
+        .and(with_format())
+        .and_then(cache_success)
+}
+
+/// Reads the counters and the size of the cache.
+async fn cache_retrieve(actor: Option<Actor>) -> Result<CacheReport, warp::reject::Rejection> {
+    require_admin(&actor)?;
+    let (capacity, entries) = cache().map_or((0, 0), |cache| (cache.entries.cap().get(), cache.entries.len()));
+
+    let hits = STATS.hits.load(Ordering::Relaxed);
+    let misses = STATS.misses.load(Ordering::Relaxed);
+    Ok(CacheReport {
+        enabled: CACHE.is_some(),
+        capacity,
+        ttl_seconds: ttl().as_secs(),
+        entries,
+        hits,
+        misses,
+        hit_ratio: if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 },
+        evictions: STATS.evictions.load(Ordering::Relaxed),
+        invalidations: STATS.invalidations.load(Ordering::Relaxed),
+    })
+}
+
+/// Replies with the cache stats.
+async fn cache_success(report: CacheReport, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(report),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+
+    fn key(path: &str) -> CacheKey {
+        cache_key(path, "", None)
+    }
+
+    fn capacity(capacity: usize) -> NonZeroUsize {
+        NonZeroUsize::new(capacity).unwrap()
+    }
+
+    #[test]
+    fn expired_results_are_dropped() {
+        let stats = Stats::default();
+        let mut cache = ResponseCache::new(capacity(2));
+        cache.store(key("/user/1"), 0, Value::from(1), &stats);
+
+        assert_eq!(cache.lookup(&key("/user/1"), Duration::from_secs(30), &stats), Some(Value::from(1)));
+        assert_eq!(cache.lookup(&key("/user/1"), Duration::ZERO, &stats), None);
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.lookup(&key("/user/2"), Duration::from_secs(30), &stats), None);
+        assert_eq!(stats.hits.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.misses.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn only_dropping_other_keys_counts_as_eviction() {
+        let stats = Stats::default();
+        let mut cache = ResponseCache::new(capacity(2));
+        cache.store(key("/user/1"), 0, Value::from(1), &stats);
+        cache.store(key("/user/2"), 0, Value::from(2), &stats);
+        cache.store(key("/user/2"), 0, Value::from(3), &stats);
+        assert_eq!(stats.evictions.load(Ordering::Relaxed), 0);
+
+        cache.store(key("/user/3"), 0, Value::from(4), &stats);
+        assert_eq!(stats.evictions.load(Ordering::Relaxed), 1);
+        // The least recently used key was dropped
+        assert!(!cache.entries.contains(&key("/user/1")));
+        assert_eq!(cache.entries.peek(&key("/user/2")).map(|entry| &entry.value), Some(&Value::from(3)));
+    }
+
+    #[test]
+    fn reads_from_before_an_invalidation_are_not_stored() {
+        let stats = Stats::default();
+        let mut cache = ResponseCache::new(capacity(2));
+        cache.store(key("/user/1"), 0, Value::from(1), &stats);
+        cache.invalidate(&stats);
+        assert!(cache.entries.is_empty());
+        assert_eq!(stats.invalidations.load(Ordering::Relaxed), 1);
+
+        cache.store(key("/user/1"), 0, Value::from(1), &stats);
+        assert!(cache.entries.is_empty());
+        cache.store(key("/user/1"), 1, Value::from(2), &stats);
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn keys_ignore_parameter_order_but_not_the_role() {
+        assert_eq!(cache_key("/user", "offset=10&limit=5&", None), cache_key("/user", "limit=5&offset=10", None));
+        assert_eq!(cache_key("/user", "limit=5&offset=10", None).query, "limit=5&offset=10");
+        assert_ne!(cache_key("/user", "", None), cache_key("/user/1", "", None));
+
+        let admin = Actor { name: "root".to_string(), role: Role::Admin };
+        let basic = Actor { name: "ann".to_string(), role: Role::Basic };
+        assert_eq!(cache_key("/user", "", Some(admin.clone())).scope, "Admin");
+        assert_eq!(cache_key("/user", "", None).scope, "anonymous");
+        assert_ne!(cache_key("/user", "", Some(admin)), cache_key("/user", "", Some(basic)));
+    }
+}
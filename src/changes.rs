@@ -0,0 +1,357 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use futures::SinkExt;
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use warp::ws::Message;
+use warp::ws::WebSocket;
+
+use crate::DB_DESIGN;
+use crate::AppError;
+use crate::ErrorType;
+use crate::db;
+use crate::db::Row;
+use crate::auth::Actor;
+use crate::auth::with_actor;
+
+/// How many changes can be waiting for a slow subscriber before it misses some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// How many of the latest changes are kept for clients that reconnect, see `subscribe_since`.
+const LOG_CAPACITY: usize = 1024;
+
+/// How many changes that fell out of the log are still remembered by their table and id,
+/// so that `subscribe_since` can count the ones a subscription missed.
+const DROPPED_CAPACITY: usize = 16 * LOG_CAPACITY;
+
+// Changes are sent to every connected subscriber through a broadcast channel.
+// Like `DB_DESIGN`, the sender is a global so that every route can publish to it.
+lazy_static! {
+    static ref CHANGES: broadcast::Sender<Change> = broadcast::channel(CHANNEL_CAPACITY).0;
+    static ref LOG: Mutex<ChangeLog> = Mutex::new(ChangeLog::default());
+}
+
+/// The latest published changes, oldest first.
+#[derive(Debug, Default)]
+struct ChangeLog {
+    /// The sequence number of the last published change.
+    seq: u64,
+    changes: VecDeque<Change>,
+    /// The sequence number, table and id of changes that fell out of `changes`, oldest first.
+    dropped: VecDeque<(u64, String, u32)>,
+}
+
+impl ChangeLog {
+    /// Adds a numbered change, moving the oldest one to `dropped` if the log is full.
+    fn push(&mut self, change: Change) {
+        if self.changes.len() == LOG_CAPACITY {
+            if let Some(oldest) = self.changes.pop_front() {
+                if self.dropped.len() == DROPPED_CAPACITY {
+                    self.dropped.pop_front();
+                }
+                self.dropped.push_back((oldest.seq, oldest.table, oldest.id));
+            }
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Returns how many changes after `last_seq` matching the subscription are no longer logged.
+    ///
+    /// Changes that are not even remembered in `dropped` are all counted, since they may have matched.
+    fn missed(&self, last_seq: u64, subscription: &Subscription) -> u64 {
+        let oldest_logged = self.changes.front().map_or(self.seq + 1, |change| change.seq);
+        let oldest_dropped = self.dropped.front().map_or(oldest_logged, |(seq, _, _)| *seq);
+        let forgotten = oldest_dropped.saturating_sub(last_seq + 1);
+        let dropped = self.dropped
+            .iter()
+            .filter(|(seq, table, id)| *seq > last_seq && subscription.matches_row(table, *id))
+            .count() as u64;
+        forgotten + dropped
+    }
+}
+
+/// A committed change to a row.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct Change {
+    /// Goes up by one for every change, so subscribers can tell if they missed any.
+    pub seq: u64,
+    pub table: String,
+    pub id: u32,
+    /// `create`, `update`, `delete` or `restore`.
+    pub action: &'static str,
+    /// The row after the change, which is left out for hard deletes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<Row>,
+}
+
+impl Change {
+    /// Creates an unpublished change, which is numbered by `publish`.
+    pub(crate) fn new(table: &str, id: u32, action: &'static str, row: Option<Row>) -> Self {
+        Change {
+            seq: 0,
+            table: table.to_string(),
+            id,
+            action,
+            row,
+        }
+    }
+
+    /// Returns the change as a client may see it.
+    ///
+    /// Like `GET /user/{id}`, which anonymous clients can read too, soft-deleted rows are only shown to admins,
+    /// so others just see that the row changed.
+    pub(crate) fn for_actor(&self, actor: &Option<Actor>) -> Change {
+        let deleted = self.row
+            .as_ref()
+            .and_then(|row| row.get(db::SOFT_DELETE_COLUMN))
+            .is_some_and(|deleted_at| !deleted_at.is_null());
+        let can_read = !deleted || actor.as_ref().is_some_and(Actor::is_admin);
+
+        let mut change = self.clone();
+        if !can_read {
+            change.row = None;
+        }
+        change
+    }
+}
+
+/// Sends committed changes to every subscriber, numbering them in order.
+pub(crate) fn publish(changes: Vec<Change>) {
+    if changes.is_empty() {
+        return;
+    }
+    // The log stays locked while sending, so that `subscribe_since` sees each change exactly once
+    let mut log = LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for mut change in changes {
+        log.seq += 1;
+        change.seq = log.seq;
+        log.push(change.clone());
+        // Sending only fails if nobody is subscribed
+        let _ = CHANGES.send(change);
+    }
+}
+
+/// Starts receiving changes published after this call.
+pub(crate) fn subscribe() -> broadcast::Receiver<Change> {
+    CHANGES.subscribe()
+}
+
+/// Starts receiving changes published after the one numbered `last_seq`.
+///
+/// Returns the logged changes since then that match the subscription, and how many matching ones were too old to be logged.
+/// Sequence numbers restart with the server, so a `last_seq` from the future replays the whole log.
+pub(crate) fn subscribe_since(last_seq: u64, subscription: &Subscription) -> (Vec<Change>, u64, broadcast::Receiver<Change>) {
+    let log = LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let receiver = CHANGES.subscribe();
+    let last_seq = if last_seq > log.seq { 0 } else { last_seq };
+
+    let missed = log.missed(last_seq, subscription);
+    let replay = log.changes
+        .iter()
+        .filter(|change| change.seq > last_seq && subscription.matches(change))
+        .cloned()
+        .collect();
+    (replay, missed, receiver)
+}
+
+/// The changes a client wants, where a missing table or id matches any.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Subscription {
+    pub table: Option<String>,
+    pub id: Option<u32>,
+}
+
+impl Subscription {
+    /// Returns whether a change is covered by this subscription.
+    pub(crate) fn matches(&self, change: &Change) -> bool {
+        self.matches_row(&change.table, change.id)
+    }
+
+    /// Returns whether changes to a row are covered by this subscription.
+    fn matches_row(&self, table: &str, id: u32) -> bool {
+        self.table.as_ref().is_none_or(|subscribed| subscribed == table)
+            && self.id.is_none_or(|subscribed| subscribed == id)
+    }
+
+    /// Errors with `400 Bad Request` if the table does not exist.
+    pub(crate) fn check(&self) -> Result<(), AppError> {
+        match &self.table {
+            Some(table) if !DB_DESIGN.tables.contains_key(table) => Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("cannot subscribe to table {}, since it does not exist", table),
+                field: Some("table".to_string()),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A message sent by a WebSocket client.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(Subscription),
+    Unsubscribe(Subscription),
+}
+
+/// A message sent to a WebSocket client.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Change(Change),
+    /// The client's subscriptions, sent on connecting and after each change to them.
+    Subscriptions { subscriptions: Vec<Subscription> },
+    /// The client was too slow to keep up, and this many changes were skipped.
+    Lagged { missed: u64 },
+    Error { message: String },
+}
+
+// GET <domain>/ws/changes?table=user&id=#
+/// A function that returns a warp route for a WebSocket feed of committed changes.
+///
+/// The query picks the first subscription (everything if it is empty), and clients can send
+/// `{"type": "subscribe", "table": "user", "id": 1}` or `{"type": "unsubscribe", ...}` to change them.
+pub(crate) fn ws_changes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("ws" / "changes")
+        .and(warp::ws())
+        .and(warp::query::<Subscription>())
+        .and(with_actor())
+        .and_then(ws_success)
+}
+
+/// Accepts the WebSocket connection if the subscription is valid.
+async fn ws_success(ws: warp::ws::Ws, subscription: Subscription, actor: Option<Actor>) -> Result<impl Reply, Rejection> {
+    subscription.check()?;
+    Ok(ws.on_upgrade(move |socket| serve(socket, vec![subscription], actor)))
+}
+
+/// Forwards matching changes to a WebSocket client until it disconnects.
+async fn serve(socket: WebSocket, mut subscriptions: Vec<Subscription>, actor: Option<Actor>) {
+    let (mut sink, mut stream) = socket.split();
+    let mut changes = subscribe();
+
+    let mut reply = Some(ServerMessage::Subscriptions { subscriptions: subscriptions.clone() });
+    loop {
+        if let Some(message) = reply.take() {
+            let text = serde_json::to_string(&message).unwrap_or_default();
+            if sink.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+
+        reply = tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(message)) => match message.to_str() {
+                    Ok(text) => Some(handle_message(text, &mut subscriptions)),
+                    // Pings are answered by warp, and other frames are ignored
+                    Err(_) => None,
+                },
+                _ => break,
+            },
+            change = changes.recv() => match change {
+                Ok(change) if subscriptions.iter().any(|subscription| subscription.matches(&change)) => {
+                    Some(ServerMessage::Change(change.for_actor(&actor)))
+                },
+                Ok(_) => None,
+                Err(RecvError::Lagged(missed)) => Some(ServerMessage::Lagged { missed }),
+                Err(RecvError::Closed) => break,
+            },
+        };
+    }
+}
+
+/// Updates the subscriptions from a client message, returning the reply.
+fn handle_message(text: &str, subscriptions: &mut Vec<Subscription>) -> ServerMessage {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(err) => return ServerMessage::Error {
+            message: format!("invalid message (expected {{\"type\": \"subscribe\" or \"unsubscribe\", \"table\", \"id\"}}): {}", err),
+        },
+    };
+
+    match message {
+        ClientMessage::Subscribe(subscription) => {
+            if let Err(err) = subscription.check() {
+                return ServerMessage::Error { message: err.message };
+            }
+            if !subscriptions.contains(&subscription) {
+                subscriptions.push(subscription);
+            }
+        },
+        ClientMessage::Unsubscribe(subscription) => subscriptions.retain(|existing| *existing != subscription),
+    }
+    ServerMessage::Subscriptions { subscriptions: subscriptions.clone() }
+}
+
+/// Describes a change feed message for the OpenAPI document.
+pub(crate) fn change_schema() -> Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "seq": { "type": "integer" },
+            "table": { "type": "string" },
+            "id": { "type": "integer" },
+            "action": { "type": "string", "enum": ["create", "update", "delete", "restore"] },
+            "row": { "type": "object" },
+        },
+        "required": ["seq", "table", "id", "action"],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+
+    fn actor(role: Role) -> Option<Actor> {
+        Some(Actor { name: "test".to_string(), role })
+    }
+
+    fn change(deleted_at: Value) -> Change {
+        let row = serde_json::json!({ "id": 1, "name": "Ann", db::SOFT_DELETE_COLUMN: deleted_at });
+        Change::new("user", 1, "update", row.as_object().cloned())
+    }
+
+    #[test]
+    fn anonymous_subscribers_get_rows_they_could_read() {
+        let change = change(Value::Null);
+        assert_eq!(change.for_actor(&None).row, change.row);
+        assert_eq!(change.for_actor(&None).id, 1);
+        assert_eq!(change.for_actor(&actor(Role::Basic)).row, change.row);
+        assert_eq!(change.for_actor(&actor(Role::Admin)).row, change.row);
+    }
+
+    #[test]
+    fn soft_deleted_rows_are_only_sent_to_admins() {
+        let change = change(Value::String("2026-10-18 12:00:00".to_string()));
+        assert_eq!(change.for_actor(&None).row, None);
+        assert_eq!(change.for_actor(&actor(Role::Mod)).row, None);
+        assert_eq!(change.for_actor(&actor(Role::Admin)).row, change.row);
+    }
+
+    #[test]
+    fn missed_changes_are_counted_per_subscription() {
+        let mut log = ChangeLog::default();
+        for seq in 1..=(LOG_CAPACITY as u64 + 10) {
+            let (table, id) = if seq % 2 == 0 { ("user", 1) } else { ("post", 2) };
+            let mut change = Change::new(table, id, "update", None);
+            change.seq = seq;
+            log.seq = seq;
+            log.push(change);
+        }
+        // Changes 1 to 10 fell out of the log, and half of them were to users
+        let users = Subscription { table: Some("user".to_string()), id: None };
+        assert_eq!(log.missed(0, &users), 5);
+        assert_eq!(log.missed(4, &users), 3);
+        assert_eq!(log.missed(10, &users), 0);
+        assert_eq!(log.missed(0, &Subscription::default()), 10);
+        assert_eq!(log.missed(0, &Subscription { table: Some("user".to_string()), id: Some(2) }), 0);
+    }
+}
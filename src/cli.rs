@@ -0,0 +1,90 @@
+use serde_json::Map;
+
+use crate::DB_DESIGN;
+use crate::AppError;
+use crate::ErrorType;
+use crate::env;
+use crate::db;
+use crate::idempotency;
+use crate::schema::Variant;
+use crate::schema::gen_json_schema;
+
+/// The usage message printed for unknown commands.
+const USAGE: &str = "usage:
+    rustful_api                            start the server
+    rustful_api schema [table] [variant]   print JSON Schemas (variant: create, update or row)
+    rustful_api migrate                    apply the pending migrations in ./migrations
+    rustful_api purge [days]               permanently delete rows soft-deleted more than [days] ago,
+                                           and expired idempotency keys";
+
+/// Runs a command-line subcommand instead of starting the server.
+pub(crate) async fn run(args: &[String]) -> Result<(), AppError> {
+    match args.first().map(String::as_str) {
+        Some("schema") => schema(&args[1..]),
+        Some("migrate") => migrate().await,
+        Some("purge") => purge(&args[1..]).await,
+        _ => Err(AppError::new(ErrorType::BadRequest, USAGE.to_string())),
+    }
+}
+
+/// Prints the JSON Schemas for one table, or for every table if none is named.
+fn schema(args: &[String]) -> Result<(), AppError> {
+    let variant = match args.get(1) {
+        Some(variant) => Some(Variant::parse(variant).ok_or_else(|| AppError::new(
+            ErrorType::BadRequest,
+            format!("unknown schema variant \"{}\"\n{}", variant, USAGE)
+        ))?),
+        None => None,
+    };
+
+    let output = match args.first() {
+        Some(table) => gen_json_schema(table, variant)?,
+        None => {
+            let mut tables: Vec<&String> = DB_DESIGN.tables.keys().collect();
+            tables.sort();
+            let mut schemas = Map::new();
+            for table in tables {
+                schemas.insert(table.to_string(), gen_json_schema(table, variant)?);
+            }
+            serde_json::Value::Object(schemas)
+        },
+    };
+
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
+
+/// Applies the pending migrations, which `GET /ready` waits for.
+async fn migrate() -> Result<(), AppError> {
+    let applied = db::run_migrations().await?;
+    if applied.is_empty() {
+        println!("migrations are up to date");
+    } else {
+        println!("applied {} migration(s): {:?}", applied.len(), applied);
+    }
+    Ok(())
+}
+
+/// Permanently deletes old soft-deleted rows from every table listed in `soft_delete`, and expired idempotency keys.
+///
+/// The retention window defaults to `soft_delete_retention_days`.
+async fn purge(args: &[String]) -> Result<(), AppError> {
+    let env = env().ok_or_else(|| AppError::new(ErrorType::Internal, "err: config is not loaded".to_string()))?;
+    let days = match args.first() {
+        Some(days) => days.parse().map_err(|_| AppError::new(
+            ErrorType::BadRequest,
+            format!("days should be a non-negative integer, not \"{}\"\n{}", days, USAGE)
+        ))?,
+        None => env.soft_delete_retention_days,
+    };
+
+    let mut conn = db::acquire().await?;
+    for table in &env.soft_delete {
+        let purged = db::purge_rows(&mut conn, table, days).await?;
+        println!("{}: purged {} rows deleted more than {} days ago", table, purged, days);
+    }
+
+    let purged = idempotency::purge(&mut conn).await?;
+    println!("purged {} expired idempotency keys", purged);
+    Ok(())
+}
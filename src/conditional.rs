@@ -0,0 +1,146 @@
+use sha2::Digest;
+use sha2::Sha256;
+use warp::Rejection;
+use warp::Filter;
+use warp::hyper::header;
+
+use crate::env;
+use crate::AppError;
+use crate::ErrorType;
+use crate::db::Row;
+
+/// Creates a strong `ETag` from the contents of a row.
+///
+/// `serde_json` keeps map keys sorted, so the same row always produces the same tag.
+pub(crate) fn etag(row: &Row) -> String {
+    let json = serde_json::to_vec(row).unwrap_or_default();
+    let digest = Sha256::digest(&json);
+    let hex: String = digest.iter().take(16).map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Creates a weak `ETag` for part of a row, such as one read with `?fields=`.
+///
+/// It answers `If-None-Match`, but never matches `If-Match`, which needs the tag of the whole row.
+pub(crate) fn weak_etag(row: &Row) -> String {
+    format!("W/{}", etag(row))
+}
+
+/// Extracts the `If-Match` header, if any.
+pub(crate) fn with_if_match() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(header::IF_MATCH.as_str())
+}
+
+/// Extracts the `If-None-Match` header, if any.
+pub(crate) fn with_if_none_match() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(header::IF_NONE_MATCH.as_str())
+}
+
+/// Returns whether an `If-Match` or `If-None-Match` list contains the tag.
+///
+/// Weak tags (`W/"..."`) are compared by their value, as `If-None-Match` requires.
+fn list_contains(list: &str, tag: &str, allow_weak: bool) -> bool {
+    list.split(',').map(str::trim).any(|candidate| {
+        if candidate == "*" {
+            return true;
+        }
+        match candidate.strip_prefix("W/") {
+            Some(weak) => allow_weak && weak == tag,
+            None => candidate == tag,
+        }
+    })
+}
+
+/// Checks an `If-Match` header against the current row before it is changed.
+///
+/// Errors with `412 Precondition Failed` if the row has changed since the client read it,
+/// or with `428 Precondition Required` if the config requires the header and it is missing.
+pub(crate) fn check_if_match(row: &Row, if_match: &Option<String>) -> Result<(), AppError> {
+    check_precondition(row, if_match, env().is_some_and(|env| env.require_if_match))
+}
+
+/// Checks an `If-Match` header like `check_if_match`, given whether the header is required.
+fn check_precondition(row: &Row, if_match: &Option<String>, required: bool) -> Result<(), AppError> {
+    match if_match {
+        Some(list) if list_contains(list, &etag(row), false) => Ok(()),
+        Some(_) => Err(AppError::new(
+            ErrorType::PreconditionFailed,
+            "Precondition Failed: the resource has changed since it was read (see the ETag header)".to_string()
+        )),
+        None if required => Err(AppError::new(
+            ErrorType::PreconditionRequired,
+            "Precondition Required: send an If-Match header with the resource's ETag".to_string()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Returns whether the client's cached copy of a row, with the given strong or weak tag, is still current.
+pub(crate) fn is_not_modified(tag: &str, if_none_match: &Option<String>) -> bool {
+    let tag = tag.strip_prefix("W/").unwrap_or(tag);
+    if_none_match
+        .as_ref()
+        .is_some_and(|list| list_contains(list, tag, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn row(name: &str) -> Row {
+        match json!({ "id": 1, "name": name }) {
+            serde_json::Value::Object(row) => row,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn tags_follow_the_row_contents() {
+        let tag = etag(&row("Ann"));
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_eq!(tag.len(), 34);
+        assert_eq!(tag, etag(&row("Ann")));
+        assert_ne!(tag, etag(&row("Bob")));
+        assert_eq!(weak_etag(&row("Ann")), format!("W/{}", tag));
+    }
+
+    #[test]
+    fn lists_match_tags_wildcards_and_weak_tags() {
+        assert!(list_contains("\"a\"", "\"a\"", false));
+        assert!(list_contains("\"x\", \"a\" ,\"y\"", "\"a\"", false));
+        assert!(list_contains("*", "\"a\"", false));
+        assert!(!list_contains("\"b\"", "\"a\"", true));
+        // Weak tags only count for If-None-Match
+        assert!(list_contains("W/\"a\"", "\"a\"", true));
+        assert!(!list_contains("W/\"a\"", "\"a\"", false));
+    }
+
+    #[test]
+    fn if_match_needs_the_current_tag() {
+        let current = row("Ann");
+        let tag = etag(&current);
+        assert!(check_precondition(&current, &Some(tag.clone()), true).is_ok());
+        assert!(check_precondition(&current, &Some("*".to_string()), true).is_ok());
+        assert!(check_precondition(&current, &None, false).is_ok());
+
+        let stale = check_precondition(&current, &Some(etag(&row("Bob"))), false).unwrap_err();
+        assert_eq!(stale.to_http_status(), warp::http::StatusCode::PRECONDITION_FAILED);
+        let weak = check_precondition(&current, &Some(format!("W/{}", tag)), false).unwrap_err();
+        assert_eq!(weak.to_http_status(), warp::http::StatusCode::PRECONDITION_FAILED);
+        let missing = check_precondition(&current, &None, true).unwrap_err();
+        assert_eq!(missing.to_http_status(), warp::http::StatusCode::PRECONDITION_REQUIRED);
+    }
+
+    #[test]
+    fn cached_copies_are_current_if_their_tag_matches() {
+        let tag = etag(&row("Ann"));
+        let weak = weak_etag(&row("Ann"));
+        assert!(is_not_modified(&tag, &Some(tag.clone())));
+        assert!(is_not_modified(&tag, &Some(weak.clone())));
+        assert!(is_not_modified(&weak, &Some(tag.clone())));
+        assert!(is_not_modified(&weak, &Some("*".to_string())));
+        assert!(!is_not_modified(&tag, &Some(etag(&row("Bob")))));
+        assert!(!is_not_modified(&tag, &None));
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::TryStreamExt;
+use serde_json::Map;
+use serde_json::Value;
+use sqlx::Column;
+use sqlx::MySql;
+use sqlx::MySqlConnection;
+use sqlx::MySqlPool;
+use sqlx::Row as SqlxRow;
+use sqlx::Transaction;
+use sqlx::TypeInfo;
+use sqlx::mysql::MySqlArguments;
+use sqlx::mysql::MySqlPoolOptions;
+use sqlx::mysql::MySqlRow;
+use sqlx::migrate::Migrator;
+use sqlx::pool::PoolConnection;
+use sqlx::query::Query;
+use lazy_static::lazy_static;
+use tokio::sync::OnceCell;
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc;
+use rustract::field::FieldDesign;
+use rustract::types::DataTypeValue;
+
+use crate::DB_DESIGN;
+use crate::env;
+use crate::AppError;
+use crate::ErrorType;
+use crate::Check;
+use crate::dump::DUMP_INFO;
+
+/// The folder `sqlx` migrations are read from.
+pub(crate) const MIGRATIONS_PATH: &str = "./migrations";
+
+/// The column that marks a row as soft-deleted, added to a table by a migration.
+///
+/// The user table's migration always adds it, but it is only used for the tables listed in `soft_delete`.
+pub(crate) const SOFT_DELETE_COLUMN: &str = "deleted_at";
+
+/// The most connections the pool opens.
+const MAX_CONNECTIONS: u32 = 5;
+
+/// The most rows that can be streamed at once, see `stream_rows`.
+///
+/// Each stream holds a connection until it ends, so this is kept below `MAX_CONNECTIONS` to leave some for other requests.
+const MAX_STREAMS: usize = 3;
+
+// The permits of `stream_rows`, shared by every streaming route.
+lazy_static! {
+    static ref STREAMS: Arc<Semaphore> = Arc::new(Semaphore::new(MAX_STREAMS));
+}
+
+// The connection pool is created once at startup, after the config has been loaded.
+// Like `DB_DESIGN`, it is stored as a global so that Warp's closures can use it.
+static POOL: OnceCell<MySqlPool> = OnceCell::const_new();
+
+/// Creates the global connection pool from the provided database url.
+///
+/// The pool connects lazily, so this will not fail if the database is currently unreachable.
+pub(crate) fn init(url: &str) -> Result<(), AppError> {
+    let pool = MySqlPoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .acquire_timeout(Duration::from_secs(3))
+        .connect_lazy(url)?;
+
+    POOL.set(pool).map_err(|_| AppError::new(
+        ErrorType::Internal,
+        "err: database pool was already initialized".to_string()
+    ))
+}
+
+/// Returns the global connection pool, or an error if no database was configured.
+pub(crate) fn pool() -> Result<&'static MySqlPool, AppError> {
+    match POOL.get() {
+        Some(pool) => Ok(pool),
+        None => Err(AppError::new(
+            ErrorType::Internal,
+            "err: no database is configured (set database_url in .env)".to_string()
+        ))
+    }
+}
+
+/// Acquires a connection from the global pool.
+pub(crate) async fn acquire() -> Result<PoolConnection<MySql>, AppError> {
+    Ok(pool()?.acquire().await?)
+}
+
+/// Starts a transaction on the global pool.
+pub(crate) async fn begin() -> Result<Transaction<'static, MySql>, AppError> {
+    Ok(pool()?.begin().await?)
+}
+
+/// Checks that the database can be reached by running a trivial query.
+pub(crate) async fn ping() -> Result<(), AppError> {
+    sqlx::query("SELECT 1").execute(pool()?).await?;
+    Ok(())
+}
+
+/// Returns the versions of any migrations that have not been applied to the database yet.
+///
+/// If the migrations folder does not exist, there is nothing to apply.
+/// If no migration was ever applied, the table `sqlx` tracks them in does not exist yet, and every migration is pending.
+pub(crate) async fn pending_migrations() -> Result<Vec<i64>, AppError> {
+    let path = Path::new(MIGRATIONS_PATH);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let migrator = Migrator::new(path).await?;
+    let applied: Vec<i64> = match sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = TRUE")
+        .fetch_all(pool()?)
+        .await
+    {
+        Ok(applied) => applied,
+        // SQLSTATE 42S02 is a missing table
+        Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("42S02") => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(migrator
+        .iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
+
+/// Applies the migrations that have not been applied yet, returning their versions.
+pub(crate) async fn run_migrations() -> Result<Vec<i64>, AppError> {
+    let pending = pending_migrations().await?;
+    if !pending.is_empty() {
+        Migrator::new(Path::new(MIGRATIONS_PATH)).await?.run(pool()?).await?;
+    }
+    Ok(pending)
+}
+
+/// A row read from the database, keyed by column name.
+pub(crate) type Row = Map<String, Value>;
+
+/// A value that can be bound to an SQL query.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SqlValue {
+    Null,
+    String(String),
+}
+
+/// A condition of a WHERE clause, with a `?` placeholder for each of its values.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Condition {
+    pub sql: String,
+    /// The values bound to the placeholders, in order.
+    pub values: Vec<SqlValue>,
+}
+
+impl Condition {
+    /// A `column = value` condition. The column should already be checked against the table.
+    pub(crate) fn equals(column: &str, value: SqlValue) -> Self {
+        Condition {
+            sql: format!("`{}` = ?", column),
+            values: vec![value],
+        }
+    }
+}
+
+/// Binds a value to the next `?` placeholder of a query.
+pub(crate) fn bind<'q>(query: Query<'q, MySql, MySqlArguments>, value: SqlValue) -> Query<'q, MySql, MySqlArguments> {
+    match value {
+        SqlValue::Null => query.bind(None::<String>),
+        SqlValue::String(value) => query.bind(value),
+    }
+}
+
+/// Returns the name of a table's primary key column.
+///
+/// Tables without a primary key in the SQL dump are assumed to use `id`.
+pub(crate) fn primary_key(table: &str) -> String {
+    DUMP_INFO
+        .get(table)
+        .and_then(|info| info.primary_key.first().cloned())
+        .unwrap_or_else(|| "id".to_string())
+}
+
+/// Describes a constraint violation reported by MySQL, by its error number and text, without repeating
+/// the text, which can contain the values of other rows.
+///
+/// Returns a fixed message and the field the violated key is on, if it can be told from the text.
+pub(crate) fn constraint_violation(number: u16, text: &str) -> (String, Option<String>) {
+    let message = match number {
+        1062 | 1586 => "conflicts with an existing row",
+        1451 => "is still referenced by other rows",
+        1452 => "references a row that does not exist",
+        1048 => "is missing a required field",
+        _ => "violates a constraint of the table",
+    };
+
+    let field = if let Some((_, key)) = text.rsplit_once(" for key ") {
+        // "Duplicate entry '...' for key 'email_unique'", with the table before the key name since MySQL 8.0.19
+        let key = key.trim_matches('\'');
+        let (table, key) = match key.split_once('.') {
+            Some((table, key)) => (Some(table), key),
+            None => (None, key),
+        };
+        DUMP_INFO
+            .iter()
+            .filter(|(name, _)| table.is_none_or(|table| table == name.as_str()))
+            .find_map(|(_, info)| if key == "PRIMARY" {
+                info.primary_key.first()
+            } else {
+                info.unique_keys.iter().find(|unique| unique.name == key).and_then(|unique| unique.columns.first())
+            })
+            .cloned()
+    } else if let Some((_, rest)) = text.split_once("FOREIGN KEY (`") {
+        rest.split_once('`').map(|(column, _)| column.to_string())
+    } else if let Some(rest) = text.strip_prefix("Column '") {
+        rest.split_once('\'').map(|(column, _)| column.to_string())
+    } else {
+        None
+    };
+
+    (message.to_string(), field)
+}
+
+/// Converts the values extracted by `post_extract` or `patch_extract` into bindable values.
+///
+/// Enums are stored by name, so their index is looked up in the field's design.
+pub(crate) fn sql_values(table: &str, values: HashMap<String, DataTypeValue>) -> Result<Vec<(String, SqlValue)>, AppError> {
+    let design = DB_DESIGN.tables.get(table).check()?;
+    let mut sql_values = Vec::new();
+
+    for (key, value) in values {
+        let field = design.fields.get(&key).check()?;
+        sql_values.push((key, sql_value(field, value)?));
+    }
+
+    // Sorted so that the generated SQL is the same for the same fields
+    sql_values.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(sql_values)
+}
+
+/// Converts a value extracted for a field into a bindable value.
+pub(crate) fn sql_value(field: &FieldDesign, value: DataTypeValue) -> Result<SqlValue, AppError> {
+    Ok(match value {
+        DataTypeValue::String(data) => SqlValue::String(data),
+        DataTypeValue::Enum(index) => {
+            let name = field.enum_set
+                .as_ref()
+                .and_then(|set| set.get(index as usize))
+                .check()?;
+            SqlValue::String(name.to_string())
+        },
+        // MySQL converts the text form of other values to the column's type
+        other => SqlValue::String(other.to_string()),
+    })
+}
+
+/// Converts a row into JSON, using the column types reported by the database.
+pub(crate) fn row_to_json(row: &MySqlRow) -> Result<Row, AppError> {
+    let mut json = Map::new();
+
+    for (index, column) in row.columns().iter().enumerate() {
+        let type_name = column.type_info().name();
+        let value = match type_name {
+            "NULL" => Value::Null,
+            "BOOLEAN" => serde_json::json!(row.try_get::<Option<bool>, _>(index)?),
+            "FLOAT" | "DOUBLE" => serde_json::json!(row.try_get::<Option<f64>, _>(index)?),
+            "TINYINT" | "SMALLINT" | "MEDIUMINT" | "INT" | "BIGINT" =>
+                serde_json::json!(row.try_get::<Option<i64>, _>(index)?),
+            "DATETIME" | "TIMESTAMP" => serde_json::json!(row
+                .try_get::<Option<chrono::DateTime<chrono::Utc>>, _>(index)?
+                .map(|time| time.to_rfc3339())),
+            "DATE" => serde_json::json!(row
+                .try_get::<Option<chrono::NaiveDate>, _>(index)?
+                .map(|date| date.to_string())),
+            _ if type_name.ends_with("UNSIGNED") =>
+                serde_json::json!(row.try_get::<Option<u64>, _>(index)?),
+            _ => serde_json::json!(row.try_get::<Option<String>, _>(index)?),
+        };
+        json.insert(column.name().to_string(), value);
+    }
+
+    Ok(json)
+}
+
+/// Returns whether a table is configured to soft delete its rows.
+pub(crate) fn soft_deletes(table: &str) -> bool {
+    env().is_some_and(|env| env.soft_delete.iter().any(|name| name == table))
+}
+
+/// Reads a row by its primary key, or errors with `404 Not Found`.
+///
+/// Soft-deleted rows are treated as missing.
+/// If `lock` is set, the row is locked until the surrounding transaction ends.
+pub(crate) async fn fetch_row(conn: &mut MySqlConnection, table: &str, id: u32, lock: bool) -> Result<Row, AppError> {
+    fetch(conn, table, None, id, lock, false).await
+}
+
+/// Reads a row by its primary key like `fetch_row`, including soft-deleted rows.
+pub(crate) async fn fetch_any_row(conn: &mut MySqlConnection, table: &str, id: u32, lock: bool) -> Result<Row, AppError> {
+    fetch(conn, table, None, id, lock, true).await
+}
+
+/// Reads only some columns of a row by its primary key, or every column if `fields` is `None`.
+///
+/// Soft-deleted rows are treated as missing unless `include_deleted` is set.
+pub(crate) async fn fetch_fields(conn: &mut MySqlConnection, table: &str, fields: Option<&[String]>, id: u32, include_deleted: bool) -> Result<Row, AppError> {
+    fetch(conn, table, fields, id, false, include_deleted).await
+}
+
+/// Reads a row by its primary key, or errors with `404 Not Found`.
+async fn fetch(conn: &mut MySqlConnection, table: &str, fields: Option<&[String]>, id: u32, lock: bool, include_deleted: bool) -> Result<Row, AppError> {
+    let sql = format!(
+        "SELECT {} FROM `{}` WHERE `{}` = ?{}{}",
+        projection(table, fields),
+        table,
+        primary_key(table),
+        if include_deleted || !soft_deletes(table) { String::new() } else { format!(" AND `{}` IS NULL", SOFT_DELETE_COLUMN) },
+        if lock { " FOR UPDATE" } else { "" }
+    );
+    match sqlx::query(&sql).bind(id).fetch_optional(conn).await? {
+        Some(row) => row_to_json(&row),
+        None => Err(AppError::new(
+            ErrorType::NotFound,
+            format!("{} #{} does not exist", table, id)
+        )),
+    }
+}
+
+/// Reads the rows matching every condition, ordered by primary key.
+///
+/// Soft-deleted rows are left out unless `include_deleted` is set, and only `fields` are read if given.
+pub(crate) async fn fetch_rows(
+    conn: &mut MySqlConnection,
+    table: &str,
+    fields: Option<&[String]>,
+    filters: Vec<Condition>,
+    include_deleted: bool,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<Row>, AppError> {
+    let sql = select_sql(table, fields, &filters, include_deleted);
+    let mut query = sqlx::query(&sql);
+    for value in filters.into_iter().flat_map(|condition| condition.values) {
+        query = bind(query, value);
+    }
+    query
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(conn)
+        .await?
+        .iter()
+        .map(row_to_json)
+        .collect()
+}
+
+/// Reads the rows matching every filter like `fetch_rows`, sending them one at a time as they arrive.
+///
+/// The rows are read from a database cursor on another task.
+/// The channel is bounded, so reading pauses while the receiver is busy, and stops if it is dropped.
+/// If `limit` is not set, every matching row is sent.
+///
+/// At most `MAX_STREAMS` streams run at once, since each holds a connection until it ends.
+/// Errors with `503 Service Unavailable` if that many are already running.
+pub(crate) fn stream_rows(
+    table: &str,
+    fields: Option<&[String]>,
+    filters: Vec<Condition>,
+    include_deleted: bool,
+    limit: Option<u32>,
+    offset: u32,
+) -> Result<mpsc::Receiver<Result<Row, AppError>>, AppError> {
+    let permit = STREAMS.clone().try_acquire_owned().map_err(|_| AppError::new(
+        ErrorType::ServiceUnavailable,
+        format!("Service Unavailable: {} streams of rows are already running, try again later", MAX_STREAMS)
+    ))?;
+    let (sender, receiver) = mpsc::channel(64);
+    let sql = select_sql(table, fields, &filters, include_deleted);
+
+    tokio::spawn(async move {
+        // The permit is released when the stream ends
+        let _permit = permit;
+        let result: Result<(), AppError> = async {
+            let mut conn = acquire().await?;
+            let mut query = sqlx::query(&sql);
+            for value in filters.into_iter().flat_map(|condition| condition.values) {
+                query = bind(query, value);
+            }
+            // MySQL has no way to leave out the limit when there is an offset, so the largest one is used
+            let mut rows = query
+                .bind(limit.map_or(u64::MAX, u64::from))
+                .bind(offset)
+                .fetch(&mut conn);
+            while let Some(row) = rows.try_next().await? {
+                if sender.send(row_to_json(&row)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }.await;
+
+        if let Err(err) = result {
+            // The receiver may be gone, in which case nobody needs the error
+            let _ = sender.send(Err(err)).await;
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// Builds the SELECT query used by `fetch_rows` and `stream_rows`, ending with `LIMIT ? OFFSET ?`.
+fn select_sql(table: &str, fields: Option<&[String]>, filters: &[Condition], include_deleted: bool) -> String {
+    format!(
+        "SELECT {} FROM `{}`{} ORDER BY `{}` LIMIT ? OFFSET ?",
+        projection(table, fields),
+        table,
+        where_sql(table, filters, include_deleted),
+        primary_key(table)
+    )
+}
+
+/// Builds the WHERE clause matching every condition, with a leading space, or an empty string if there is none.
+///
+/// Soft-deleted rows are left out unless `include_deleted` is set.
+/// The values of the conditions should be bound in order.
+pub(crate) fn where_sql(table: &str, filters: &[Condition], include_deleted: bool) -> String {
+    let mut conditions: Vec<String> = filters.iter().map(|condition| condition.sql.clone()).collect();
+    if !include_deleted && soft_deletes(table) {
+        conditions.push(format!("`{}` IS NULL", SOFT_DELETE_COLUMN));
+    }
+    if conditions.is_empty() { String::new() } else { format!(" WHERE {}", conditions.join(" AND ")) }
+}
+
+/// Builds the column list of a SELECT query: every column, or only the given columns for sparse fieldsets.
+///
+/// If the table does not soft delete, its fields are listed instead of `*`, so that a `deleted_at` column
+/// added by the migrations is not returned, nor part of the `ETag`.
+/// The columns should already be checked against the table, e.g. by `list::parse_fields`.
+pub(crate) fn projection(table: &str, fields: Option<&[String]>) -> String {
+    let columns: Vec<&String> = match (fields, DB_DESIGN.tables.get(table)) {
+        (Some(fields), _) => fields.iter().collect(),
+        (None, Some(design)) if !soft_deletes(table) => {
+            let mut columns: Vec<&String> = design.fields.keys().collect();
+            columns.sort();
+            columns
+        },
+        (None, _) => return "*".to_string(),
+    };
+    columns.iter().map(|column| format!("`{}`", column)).collect::<Vec<String>>().join(", ")
+}
+
+/// Inserts a row and returns its generated id.
+pub(crate) async fn insert_row(conn: &mut MySqlConnection, table: &str, values: Vec<(String, SqlValue)>) -> Result<u64, AppError> {
+    let columns: Vec<String> = values.iter().map(|(key, _)| format!("`{}`", key)).collect();
+    let placeholders = vec!["?"; values.len()];
+    let sql = format!(
+        "INSERT INTO `{}` ({}) VALUES ({})",
+        table,
+        columns.join(", "),
+        placeholders.join(", ")
+    );
+
+    let mut query = sqlx::query(&sql);
+    for (_, value) in values {
+        query = bind(query, value);
+    }
+    Ok(query.execute(conn).await?.last_insert_id())
+}
+
+/// Updates the given columns of a row.
+///
+/// Does nothing if there are no columns to update.
+pub(crate) async fn update_row(conn: &mut MySqlConnection, table: &str, id: u32, values: Vec<(String, SqlValue)>) -> Result<(), AppError> {
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let assignments: Vec<String> = values.iter().map(|(key, _)| format!("`{}` = ?", key)).collect();
+    let sql = format!(
+        "UPDATE `{}` SET {} WHERE `{}` = ?",
+        table,
+        assignments.join(", "),
+        primary_key(table)
+    );
+
+    let mut query = sqlx::query(&sql);
+    for (_, value) in values {
+        query = bind(query, value);
+    }
+    query.bind(id).execute(conn).await?;
+    Ok(())
+}
+
+/// Deletes a row by its primary key.
+pub(crate) async fn delete_row(conn: &mut MySqlConnection, table: &str, id: u32) -> Result<(), AppError> {
+    let sql = format!("DELETE FROM `{}` WHERE `{}` = ?", table, primary_key(table));
+    sqlx::query(&sql).bind(id).execute(conn).await?;
+    Ok(())
+}
+
+/// Marks a row as deleted, or clears the mark if `deleted` is false.
+pub(crate) async fn set_deleted(conn: &mut MySqlConnection, table: &str, id: u32, deleted: bool) -> Result<(), AppError> {
+    let sql = format!(
+        "UPDATE `{}` SET `{}` = {} WHERE `{}` = ?",
+        table,
+        SOFT_DELETE_COLUMN,
+        if deleted { "UTC_TIMESTAMP()" } else { "NULL" },
+        primary_key(table)
+    );
+    sqlx::query(&sql).bind(id).execute(conn).await?;
+    Ok(())
+}
+
+/// Permanently deletes the rows of a table that were soft-deleted more than `days` days ago.
+///
+/// Returns the number of rows deleted.
+pub(crate) async fn purge_rows(conn: &mut MySqlConnection, table: &str, days: u32) -> Result<u64, AppError> {
+    let sql = format!(
+        "DELETE FROM `{}` WHERE `{}` < UTC_TIMESTAMP() - INTERVAL ? DAY",
+        table,
+        SOFT_DELETE_COLUMN
+    );
+    Ok(sqlx::query(&sql).bind(days).execute(conn).await?.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constraint_violations_name_the_field() {
+        assert_eq!(
+            constraint_violation(1062, "Duplicate entry 'ann@example.com' for key 'email_unique'"),
+            ("conflicts with an existing row".to_string(), Some("email".to_string()))
+        );
+        assert_eq!(
+            constraint_violation(1062, "Duplicate entry '3' for key 'user.PRIMARY'"),
+            ("conflicts with an existing row".to_string(), Some("id".to_string()))
+        );
+        assert_eq!(
+            constraint_violation(1452, "Cannot add or update a child row: a foreign key constraint fails (`rustful`.`post`, \
+                CONSTRAINT `post_user` FOREIGN KEY (`user_id`) REFERENCES `user` (`id`))"),
+            ("references a row that does not exist".to_string(), Some("user_id".to_string()))
+        );
+        assert_eq!(
+            constraint_violation(1048, "Column 'name' cannot be null"),
+            ("is missing a required field".to_string(), Some("name".to_string()))
+        );
+    }
+
+    #[test]
+    fn streams_are_limited() {
+        let permits = STREAMS.clone().try_acquire_many_owned(MAX_STREAMS as u32).expect("no streams should be running");
+        let err = stream_rows("user", None, Vec::new(), false, None, 0).expect_err("every permit is taken");
+        assert_eq!(err.to_http_status(), warp::http::StatusCode::SERVICE_UNAVAILABLE);
+        drop(permits);
+    }
+
+    #[test]
+    fn unknown_constraints_keep_a_fixed_message() {
+        assert_eq!(
+            constraint_violation(1062, "Duplicate entry 'secret' for key 'other.missing_key'"),
+            ("conflicts with an existing row".to_string(), None)
+        );
+        assert_eq!(constraint_violation(4025, "CONSTRAINT `age` failed for `rustful`.`user`"), ("violates a constraint of the table".to_string(), None));
+    }
+
+    #[test]
+    fn projections_leave_out_deleted_at_without_soft_deletes() {
+        // No config is loaded in tests, so no table soft deletes
+        assert_eq!(projection("user", None), "`email`, `id`, `name`, `registered`, `type`");
+        assert_eq!(projection("user", Some(&["name".to_string(), "id".to_string()])), "`name`, `id`");
+        assert_eq!(projection("audit_log", None), "*");
+    }
+}
@@ -1,37 +1,77 @@
 use warp::Rejection;
 use warp::Reply;
 use warp::Filter;
+use sqlx::MySqlConnection;
 
+use crate::AppError;
+use crate::db;
+use crate::db::Row;
 use crate::routes::respond;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::conditional::check_if_match;
+use crate::conditional::with_if_match;
+use crate::audit;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
 
 // DELETE <domain>/user/#
 /// A function that returns a warp route for deleting a user.
+/// 
+/// An `If-Match` header is checked against the user's current `ETag`.
+/// If the user table soft deletes, the user is only marked as deleted (see `restore_user`).
 pub(crate) fn delete_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("user" / u32)
         .and(warp::delete())
+        .and(check_accept())
+        .and(with_if_match())
+        .and(with_audit())
         .and_then(delete_retrieve)
+        .and(with_format())
         .and_then(delete_success)
 }
 
-/// Uses the id to make an SQL DELETE query.
-async fn delete_retrieve(id: u32) -> Result<String, warp::reject::Rejection> {
-    // An SQL query can be made here that safely deletes user data
-    println!(
-        "User #{} deleted",
-        id
-    );
+/// Uses the id to make an SQL DELETE query, or an UPDATE setting `deleted_at` for soft deletes.
+/// 
+/// The deleted row is passed on so that it can be included in the reply.
+async fn delete_retrieve(id: u32, if_match: Option<String>, context: AuditContext) -> Result<Row, warp::reject::Rejection> {
+    let mut tx = db::begin().await?;
+    let user = remove_user(&mut tx, id, &if_match, &context).await?;
+    tx.commit().await.map_err(AppError::from)?;
+    context.publish();
 
-    // The id is passed on for the hello world filter to consume
-    Ok(id.to_string())
+    Ok(user)
 }
 
-/// Replies with a success code and DELETE-related message.
-async fn delete_success(user_id: String) -> Result<impl Reply, Rejection> {
+/// Deletes or soft deletes a user and records it in the audit log.
+pub(crate) async fn remove_user(conn: &mut MySqlConnection, id: u32, if_match: &Option<String>, context: &AuditContext) -> Result<Row, AppError> {
+    remove_row(conn, "user", id, if_match, context).await
+}
+
+/// Deletes or soft deletes a row of any table and records it in the audit log.
+/// 
+/// Returns the row as it was before a hard delete, or with its `deleted_at` mark after a soft delete.
+pub(crate) async fn remove_row(conn: &mut MySqlConnection, table: &str, id: u32, if_match: &Option<String>, context: &AuditContext) -> Result<Row, AppError> {
+    let current = db::fetch_row(conn, table, id, true).await?;
+    check_if_match(&current, if_match)?;
+    if db::soft_deletes(table) {
+        db::set_deleted(conn, table, id, true).await?;
+        let row = db::fetch_any_row(conn, table, id, false).await?;
+        audit::record(conn, context, table, id, audit::Action::Delete, Some(&current), Some(&row)).await?;
+        Ok(row)
+    } else {
+        db::delete_row(conn, table, id).await?;
+        audit::record(conn, context, table, id, audit::Action::Delete, Some(&current), None).await?;
+        Ok(current)
+    }
+}
+
+/// Replies with the deleted user.
+async fn delete_success(user: Row, format: Format) -> Result<impl Reply, Rejection> {
     respond(
-        Ok(format!(
-            "Goodbye, User #{}. If this was hooked up to a database, your information would be deleted.",
-            user_id)
-        ),
-        warp::http::StatusCode::ACCEPTED
+        Ok(user),
+        warp::http::StatusCode::OK,
+        format
     )
 }
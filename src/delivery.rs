@@ -0,0 +1,326 @@
+use std::time::Duration;
+use hmac::Hmac;
+use hmac::Mac;
+use lazy_static::lazy_static;
+use sha2::Sha256;
+use sqlx::Row as SqlxRow;
+use sqlx::mysql::MySqlRow;
+use tokio::sync::Notify;
+
+use crate::env;
+use crate::AppError;
+use crate::db;
+use crate::webhooks::DELIVERY_TABLE;
+
+/// How long a webhook has to answer before the attempt counts as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the queue is checked for retries when nothing wakes the worker.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many deliveries are sent at once.
+const BATCH_SIZE: u32 = 20;
+
+/// How long a claimed delivery is held, so that another server does not send it at the same time.
+const CLAIM_SECONDS: u64 = 60;
+
+/// The wait before the first retry, which doubles after each failed attempt.
+const BASE_BACKOFF_SECONDS: u64 = 30;
+
+/// The longest wait between two attempts.
+const MAX_BACKOFF_SECONDS: u64 = 6 * 60 * 60;
+
+// The client and the wake-up signal are shared by the worker and the routes.
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("webhook client should build");
+    static ref WAKE: Notify = Notify::new();
+}
+
+/// A queued delivery, with the webhook it is sent to.
+struct Delivery {
+    id: u64,
+    action: String,
+    payload: String,
+    attempts: u32,
+    url: String,
+    secret: String,
+}
+
+impl Delivery {
+    fn from_row(row: &MySqlRow) -> Result<Delivery, AppError> {
+        Ok(Delivery {
+            id: row.try_get("id")?,
+            action: row.try_get("action")?,
+            payload: row.try_get("payload")?,
+            attempts: row.try_get("attempts")?,
+            url: row.try_get("url")?,
+            secret: row.try_get("secret")?,
+        })
+    }
+}
+
+/// Returns how many times a delivery is attempted before it is marked as dead.
+fn max_attempts() -> u32 {
+    env().map_or(8, |env| env.webhook_max_attempts)
+}
+
+/// Tells the worker that deliveries were queued, so that they are sent without waiting for the next poll.
+pub(crate) fn wake() {
+    WAKE.notify_one();
+}
+
+/// Sends queued deliveries until the server stops.
+///
+/// Failed deliveries are retried with exponential backoff, and kept with the `dead` state once
+/// `webhook_max_attempts` have failed. The queue is stored in the database, so nothing is lost on restart.
+pub(crate) async fn run() {
+    loop {
+        match deliver_due().await {
+            // A full batch means more deliveries may be waiting
+            Ok(sent) if sent == BATCH_SIZE as usize => continue,
+            Ok(_) => {},
+            Err(err) => eprintln!("webhook delivery failed: {}", err.message),
+        }
+        let _ = tokio::time::timeout(POLL_INTERVAL, WAKE.notified()).await;
+    }
+}
+
+/// Claims the deliveries that are due and sends them, returning how many were found.
+async fn deliver_due() -> Result<usize, AppError> {
+    let mut conn = db::acquire().await?;
+    let sql = format!(
+        "SELECT d.`id`, d.`action`, d.`payload`, d.`attempts`, w.`url`, w.`secret` FROM `{}` d \
+         JOIN `webhooks` w ON w.`id` = d.`webhook_id` \
+         WHERE d.`state` = 'pending' AND d.`next_attempt_at` <= UTC_TIMESTAMP() ORDER BY d.`id` LIMIT ?",
+        DELIVERY_TABLE
+    );
+    let rows = sqlx::query(&sql)
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut conn)
+        .await?;
+
+    let mut claimed = Vec::new();
+    for row in rows.iter() {
+        let delivery = Delivery::from_row(row)?;
+        if claim(&mut conn, delivery.id).await? {
+            claimed.push(delivery);
+        }
+    }
+    drop(conn);
+
+    let found = rows.len();
+    let results = futures::future::join_all(claimed.into_iter().map(deliver)).await;
+    for result in results {
+        if let Err(err) = result {
+            eprintln!("webhook delivery failed: {}", err.message);
+        }
+    }
+    Ok(found)
+}
+
+/// Holds a delivery for `CLAIM_SECONDS`, returning false if another server claimed it first.
+async fn claim(conn: &mut sqlx::MySqlConnection, id: u64) -> Result<bool, AppError> {
+    let sql = format!(
+        "UPDATE `{}` SET `next_attempt_at` = UTC_TIMESTAMP() + INTERVAL ? SECOND \
+         WHERE `id` = ? AND `state` = 'pending' AND `next_attempt_at` <= UTC_TIMESTAMP()",
+        DELIVERY_TABLE
+    );
+    let result = sqlx::query(&sql)
+        .bind(CLAIM_SECONDS)
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Sends one delivery and records the outcome of the attempt.
+async fn deliver(delivery: Delivery) -> Result<(), AppError> {
+    let (status, error) = send(&delivery).await;
+    let attempts = delivery.attempts + 1;
+    let state = next_state(error.is_none(), attempts, max_attempts());
+    let sql = format!(
+        "UPDATE `{}` SET `state` = ?, `attempts` = ?, `response_status` = ?, `last_error` = ?, \
+         `next_attempt_at` = UTC_TIMESTAMP() + INTERVAL ? SECOND, \
+         `delivered_at` = CASE WHEN ? THEN UTC_TIMESTAMP() END WHERE `id` = ?",
+        DELIVERY_TABLE
+    );
+    let mut conn = db::acquire().await?;
+    sqlx::query(&sql)
+        .bind(state)
+        .bind(attempts)
+        .bind(status)
+        .bind(error)
+        .bind(backoff_seconds(attempts))
+        .bind(state == "delivered")
+        .bind(delivery.id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Posts a delivery to its webhook, returning the response status and, if the attempt failed, why.
+async fn send(delivery: &Delivery) -> (Option<u16>, Option<String>) {
+    let timestamp = chrono::Utc::now().timestamp();
+    let signature = sign(&delivery.secret, timestamp, &delivery.payload);
+    let response = CLIENT
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("x-webhook-id", delivery.id.to_string())
+        .header("x-webhook-event", &delivery.action)
+        .header("x-webhook-timestamp", timestamp.to_string())
+        .header("x-webhook-signature", format!("sha256={}", signature))
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+        Ok(response) => (Some(response.status().as_u16()), Some(format!("webhook answered {}", response.status()))),
+        Err(err) => (None, Some(err.to_string())),
+    }
+}
+
+/// Returns the state of a delivery after an attempt: `delivered`, `pending` for another try,
+/// or `dead` once `max_attempts` attempts have failed.
+fn next_state(succeeded: bool, attempts: u32, max_attempts: u32) -> &'static str {
+    if succeeded {
+        "delivered"
+    } else if attempts >= max_attempts {
+        "dead"
+    } else {
+        "pending"
+    }
+}
+
+/// Returns how long to wait after a number of failed attempts: 30 seconds, then a minute, two, and so on.
+fn backoff_seconds(attempts: u32) -> u64 {
+    BASE_BACKOFF_SECONDS
+        .saturating_mul(1u64 << attempts.saturating_sub(1).min(32))
+        .min(MAX_BACKOFF_SECONDS)
+}
+
+/// Signs a payload with HMAC-SHA256 over `"{timestamp}.{payload}"`, as a hex string.
+///
+/// Receivers can recompute this with their secret, and reject old timestamps to stop replays.
+fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    // HMAC accepts keys of any length, so this cannot fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC should accept any key");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use warp::Filter;
+    use super::*;
+
+    /// The headers and body of a request to the test receiver.
+    type Received = (HashMap<String, String>, String);
+
+    fn delivery(url: String) -> Delivery {
+        Delivery {
+            id: 7,
+            action: "create".to_string(),
+            payload: r#"{"event_id":1,"action":"create"}"#.to_string(),
+            attempts: 0,
+            url,
+            secret: "whsec_test".to_string(),
+        }
+    }
+
+    /// Starts a receiver on a free local port that answers with `status`, and returns its url
+    /// and a channel of the headers and body of each request.
+    fn receiver(status: u16) -> (String, tokio::sync::mpsc::UnboundedReceiver<Received>) {
+        let (sender, requests) = tokio::sync::mpsc::unbounded_channel();
+        let route = warp::post()
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers: warp::http::HeaderMap, body: warp::hyper::body::Bytes| {
+                let headers = headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or_default().to_string()))
+                    .collect();
+                sender.send((headers, String::from_utf8_lossy(&body).to_string())).ok();
+                warp::http::StatusCode::from_u16(status).unwrap()
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/hook", address), requests)
+    }
+
+    #[test]
+    fn sign_matches_known_vector() {
+        // Computed independently with Python's hmac module
+        assert_eq!(
+            sign("whsec_test", 1700000000, r#"{"event_id":1,"action":"create"}"#),
+            "c1a47e46959c6487e740e287ea23ff994ca1a3a51c0e79f58e65ccfd5965eef4"
+        );
+        assert_ne!(
+            sign("whsec_other", 1700000000, r#"{"event_id":1,"action":"create"}"#),
+            sign("whsec_test", 1700000000, r#"{"event_id":1,"action":"create"}"#)
+        );
+        assert_ne!(
+            sign("whsec_test", 1700000001, r#"{"event_id":1,"action":"create"}"#),
+            sign("whsec_test", 1700000000, r#"{"event_id":1,"action":"create"}"#)
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff_seconds(0), 30);
+        assert_eq!(backoff_seconds(1), 30);
+        assert_eq!(backoff_seconds(2), 60);
+        assert_eq!(backoff_seconds(3), 120);
+        assert_eq!(backoff_seconds(10), 15360);
+        assert_eq!(backoff_seconds(11), MAX_BACKOFF_SECONDS);
+        assert_eq!(backoff_seconds(u32::MAX), MAX_BACKOFF_SECONDS);
+    }
+
+    #[test]
+    fn delivery_dies_after_max_attempts() {
+        let max_attempts = 5;
+        let mut attempts = 0;
+        let mut state = "pending";
+        while state == "pending" {
+            attempts += 1;
+            state = next_state(false, attempts, max_attempts);
+        }
+        assert_eq!((state, attempts), ("dead", max_attempts));
+        assert_eq!(next_state(true, max_attempts, max_attempts), "delivered");
+        assert_eq!(next_state(false, 1, 1), "dead");
+    }
+
+    #[tokio::test]
+    async fn send_signs_the_request() {
+        let (url, mut requests) = receiver(204);
+        let delivery = delivery(url);
+        assert_eq!(send(&delivery).await, (Some(204), None));
+
+        let (headers, body) = requests.recv().await.expect("the receiver should get the delivery");
+        assert_eq!(body, delivery.payload);
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-webhook-id"], "7");
+        assert_eq!(headers["x-webhook-event"], "create");
+        let timestamp: i64 = headers["x-webhook-timestamp"].parse().expect("the timestamp should be a number");
+        assert_eq!(headers["x-webhook-signature"], format!("sha256={}", sign("whsec_test", timestamp, &body)));
+    }
+
+    #[tokio::test]
+    async fn send_reports_failures() {
+        let (url, _requests) = receiver(500);
+        let (status, error) = send(&delivery(url)).await;
+        assert_eq!(status, Some(500));
+        assert_eq!(error.as_deref(), Some("webhook answered 500 Internal Server Error"));
+
+        let (status, error) = send(&delivery("http://127.0.0.1:1/hook".to_string())).await;
+        assert_eq!(status, None);
+        assert!(error.is_some());
+    }
+}
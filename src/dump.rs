@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use lazy_static::lazy_static;
+
+// Details that rustract does not keep from the SQL dump, such as defaults and key names.
+// Like `DB_DESIGN`, this is parsed once and used as a global.
+lazy_static! {
+    pub static ref DUMP_INFO: HashMap<String, TableInfo> = parse_dump(
+        &std::fs::read_to_string("./db_dump.sql").expect("failed to read db_dump.sql")
+    );
+}
+
+/// The column definition of a field, as written in the SQL dump.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ColumnInfo {
+    /// The SQL type, e.g. `varchar(45)` or `int(10) unsigned`.
+    pub sql_type: String,
+    /// The default value, e.g. `NULL` or `'Basic'`.
+    pub default: Option<String>,
+}
+
+/// A named index over one or more columns.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct KeyInfo {
+    pub name: String,
+    pub columns: Vec<String>,
+}
+
+/// A foreign key constraint, pointing columns of this table at another table.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ForeignKeyInfo {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub references_table: String,
+    pub references_columns: Vec<String>,
+}
+
+/// The details of a table's `CREATE TABLE` statement.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct TableInfo {
+    pub columns: HashMap<String, ColumnInfo>,
+    pub primary_key: Vec<String>,
+    pub unique_keys: Vec<KeyInfo>,
+    pub foreign_keys: Vec<ForeignKeyInfo>,
+}
+
+/// Parses the `CREATE TABLE` statements of a MySQL/MariaDB dump.
+///
+/// Only the statement layout produced by `mysqldump` is supported,
+/// with one column or key definition per line.
+pub fn parse_dump(sql: &str) -> HashMap<String, TableInfo> {
+    let mut tables = HashMap::new();
+    let mut current: Option<(String, TableInfo)> = None;
+
+    for line in sql.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("CREATE TABLE ") {
+            let name = names(rest).into_iter().next().unwrap_or_default();
+            current = Some((name, TableInfo::default()));
+            continue;
+        }
+
+        let (_, table) = match current.as_mut() {
+            Some(current) => current,
+            None => continue,
+        };
+
+        // The statement ends with the table options, e.g. `) ENGINE=InnoDB ...;`
+        if line.starts_with(')') {
+            if let Some((name, table)) = current.take() {
+                tables.insert(name, table);
+            }
+        } else if let Some(rest) = line.strip_prefix("PRIMARY KEY ") {
+            table.primary_key = names(rest);
+        } else if let Some(rest) = line.strip_prefix("UNIQUE KEY ") {
+            let mut names = names(rest);
+            if !names.is_empty() {
+                let name = names.remove(0);
+                table.unique_keys.push(KeyInfo { name, columns: names });
+            }
+        } else if let Some(rest) = line.strip_prefix("CONSTRAINT ") {
+            if let Some(foreign) = parse_foreign_key(rest) {
+                table.foreign_keys.push(foreign);
+            }
+        } else if line.starts_with('`') {
+            let mut parts = line.splitn(3, '`');
+            parts.next();
+            let name = parts.next().unwrap_or_default().to_string();
+            let definition = parts.next().unwrap_or_default().trim().trim_end_matches(',');
+            table.columns.insert(name, parse_column(definition));
+        }
+    }
+
+    tables
+}
+
+/// Parses a column definition such as `varchar(10) DEFAULT NULL`.
+fn parse_column(definition: &str) -> ColumnInfo {
+    // The type ends at the first constraint keyword
+    let keywords = [" NOT NULL", " NULL", " DEFAULT ", " AUTO_INCREMENT", " COLLATE ", " CHARACTER SET ", " COMMENT "];
+    let type_end = keywords
+        .iter()
+        .filter_map(|keyword| definition.find(keyword))
+        .min()
+        .unwrap_or(definition.len());
+
+    let default = definition.find(" DEFAULT ").map(|start| {
+        let value = &definition[start + " DEFAULT ".len()..];
+        if let Some(quoted) = value.strip_prefix('\'') {
+            let end = quoted.find('\'').unwrap_or(quoted.len());
+            format!("'{}'", &quoted[..end])
+        } else {
+            value.split_whitespace().next().unwrap_or_default().to_string()
+        }
+    });
+
+    ColumnInfo {
+        sql_type: definition[..type_end].trim().to_string(),
+        default,
+    }
+}
+
+/// Parses a constraint such as `` `fk` FOREIGN KEY (`a`) REFERENCES `t` (`b`) ``.
+fn parse_foreign_key(constraint: &str) -> Option<ForeignKeyInfo> {
+    let (local, remote) = constraint.split_once(" FOREIGN KEY ")?;
+    let (columns, references) = remote.split_once(" REFERENCES ")?;
+    let mut references = names(references);
+    if references.is_empty() {
+        return None;
+    }
+
+    Some(ForeignKeyInfo {
+        name: names(local).into_iter().next().unwrap_or_default(),
+        columns: names(columns),
+        references_table: references.remove(0),
+        references_columns: references,
+    })
+}
+
+/// Returns every backtick-quoted name in a fragment of SQL, in order.
+fn names(sql: &str) -> Vec<String> {
+    sql.split('`')
+        .skip(1)
+        .step_by(2)
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A dump with tables referencing `user.id`, since `db_dump.sql` has no foreign keys.
+    pub(crate) const RELATED_DUMP: &str = "
+CREATE TABLE `user` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `name` varchar(45) NOT NULL,
+  PRIMARY KEY (`id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `post` (
+  `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `author_id` int(10) unsigned NOT NULL,
+  `created_by` int(10) unsigned DEFAULT NULL,
+  `updated_by` int(10) unsigned DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  CONSTRAINT `post_author` FOREIGN KEY (`author_id`) REFERENCES `user` (`id`) ON DELETE CASCADE,
+  CONSTRAINT `post_created_by` FOREIGN KEY (`created_by`) REFERENCES `user` (`id`),
+  CONSTRAINT `post_updated_by` FOREIGN KEY (`updated_by`) REFERENCES `user` (`id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `tag` (
+  `post_id` int(10) unsigned NOT NULL,
+  `user_id` int(10) unsigned NOT NULL,
+  PRIMARY KEY (`post_id`,`user_id`),
+  CONSTRAINT `tag_pair` FOREIGN KEY (`post_id`, `user_id`) REFERENCES `pair` (`post_id`, `user_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+";
+
+    #[test]
+    fn foreign_keys_are_parsed() {
+        let key = parse_foreign_key("`post_author` FOREIGN KEY (`author_id`) REFERENCES `user` (`id`) ON DELETE CASCADE,").unwrap();
+        assert_eq!(key.name, "post_author");
+        assert_eq!(key.columns, ["author_id"]);
+        assert_eq!(key.references_table, "user");
+        assert_eq!(key.references_columns, ["id"]);
+
+        let key = parse_foreign_key("`tag_pair` FOREIGN KEY (`post_id`, `user_id`) REFERENCES `pair` (`post_id`, `user_id`)").unwrap();
+        assert_eq!(key.columns, ["post_id", "user_id"]);
+        assert_eq!(key.references_columns, ["post_id", "user_id"]);
+
+        assert!(parse_foreign_key("`chk` CHECK (`id` > 0)").is_none());
+        assert!(parse_foreign_key("`fk` FOREIGN KEY (`a`) REFERENCES ").is_none());
+    }
+
+    #[test]
+    fn foreign_keys_are_read_from_the_dump() {
+        let tables = parse_dump(RELATED_DUMP);
+        let names: Vec<&str> = tables["post"].foreign_keys.iter().map(|key| key.name.as_str()).collect();
+        assert_eq!(names, ["post_author", "post_created_by", "post_updated_by"]);
+        assert!(tables["user"].foreign_keys.is_empty());
+        assert_eq!(tables["tag"].foreign_keys[0].references_table, "pair");
+    }
+
+    #[test]
+    fn the_repo_dump_is_parsed() {
+        let tables = parse_dump(&std::fs::read_to_string("./db_dump.sql").unwrap());
+        assert_eq!(tables.len(), 1);
+        let user = &tables["user"];
+        assert_eq!(user.columns.len(), 5);
+        assert_eq!(user.columns["id"].sql_type, "int(10) unsigned");
+        assert_eq!(user.columns["id"].default, None);
+        assert_eq!(user.columns["registered"].sql_type, "varchar(10)");
+        assert_eq!(user.columns["registered"].default.as_deref(), Some("NULL"));
+        assert_eq!(user.columns["type"].sql_type, "enum('Admin','Mod','Basic')");
+        assert_eq!(user.primary_key, ["id"]);
+        let unique: Vec<(&str, &[String])> = user.unique_keys.iter().map(|key| (key.name.as_str(), key.columns.as_slice())).collect();
+        assert_eq!(unique, [("id_UNIQUE", &["id".to_string()][..]), ("email_unique", &["email".to_string()][..])]);
+        assert!(user.foreign_keys.is_empty());
+    }
+
+    #[test]
+    fn columns_are_split_into_type_and_default() {
+        let column = parse_column("varchar(45) COLLATE utf8mb4_bin DEFAULT 'a b, c' COMMENT 'x'");
+        assert_eq!(column.sql_type, "varchar(45)");
+        assert_eq!(column.default.as_deref(), Some("'a b, c'"));
+
+        let column = parse_column("enum('Admin','Mod','Basic') NOT NULL DEFAULT 'Basic'");
+        assert_eq!(column.sql_type, "enum('Admin','Mod','Basic')");
+        assert_eq!(column.default.as_deref(), Some("'Basic'"));
+
+        let column = parse_column("timestamp NULL DEFAULT current_timestamp() ON UPDATE current_timestamp()");
+        assert_eq!(column.sql_type, "timestamp");
+        assert_eq!(column.default.as_deref(), Some("current_timestamp()"));
+
+        let column = parse_column("double");
+        assert_eq!(column.sql_type, "double");
+        assert_eq!(column.default, None);
+    }
+
+    #[test]
+    fn names_are_read_between_backticks() {
+        assert_eq!(names("`fk` (`a`, `b`)"), ["fk", "a", "b"]);
+        assert_eq!(names("`user` (\n"), ["user"]);
+        assert!(names("(id)").is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use futures::Stream;
+use futures::StreamExt;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use warp::sse::Event;
+
+use crate::changes;
+use crate::changes::Change;
+use crate::changes::Subscription;
+use crate::auth::Actor;
+use crate::auth::with_actor;
+
+/// How often a comment is sent on idle streams, so that proxies do not close them.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The query of an event stream, e.g. `?id=1` for the changes to one user.
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct EventsQuery {
+    pub id: Option<u32>,
+}
+
+// GET <domain>/user/events?id=#
+/// A function that returns a warp route for a Server-Sent Events stream of committed user changes.
+///
+/// Each event's id is the change's `seq`, so a client that reconnects with `Last-Event-ID`
+/// first receives the changes it missed, as long as they are still in the change log.
+/// Otherwise a `lagged` event counts the missed changes to the subscribed users.
+/// Like the WebSocket feed, soft-deleted rows are left out for clients that are not admins.
+pub(crate) fn user_events() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / "events")
+        .and(warp::get())
+        .and(warp::query::<EventsQuery>())
+        .and(warp::sse::last_event_id::<u64>())
+        .and(with_actor())
+        .and_then(events_success)
+}
+
+/// Streams the logged changes after `Last-Event-ID`, then new changes as they are published.
+async fn events_success(query: EventsQuery, last_event_id: Option<u64>, actor: Option<Actor>) -> Result<impl Reply, Rejection> {
+    let subscription = Subscription { table: Some("user".to_string()), id: query.id };
+    let (replay, missed, receiver) = match last_event_id {
+        Some(last_seq) => changes::subscribe_since(last_seq, &subscription),
+        None => (Vec::new(), 0, changes::subscribe()),
+    };
+
+    let events = replay_events(&replay, missed, &subscription, &actor);
+    let stream = futures::stream::iter(events).chain(live_events(receiver, subscription, actor));
+    Ok(warp::sse::reply(
+        warp::sse::keep_alive()
+            .interval(KEEP_ALIVE_INTERVAL)
+            .stream(stream.map(Ok::<Event, Infallible>))
+    ))
+}
+
+/// Turns the logged changes that match a subscription into events, after a `lagged` event if some were missed.
+fn replay_events(replay: &[Change], missed: u64, subscription: &Subscription, actor: &Option<Actor>) -> Vec<Event> {
+    let mut events = Vec::new();
+    if missed > 0 {
+        events.push(lagged_event(missed));
+    }
+    events.extend(
+        replay
+            .iter()
+            .filter(|change| subscription.matches(change))
+            .map(|change| change_event(&change.for_actor(actor)))
+    );
+    events
+}
+
+/// Turns the published changes that match a subscription into events, until the channel closes.
+fn live_events(receiver: broadcast::Receiver<Change>, subscription: Subscription, actor: Option<Actor>) -> impl Stream<Item = Event> {
+    futures::stream::unfold(receiver, move |mut receiver| {
+        let subscription = subscription.clone();
+        let actor = actor.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(change) if subscription.matches(&change) => {
+                        return Some((change_event(&change.for_actor(&actor)), receiver));
+                    },
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => return Some((lagged_event(missed), receiver)),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
+
+/// Describes a change as a `change` event, with its `seq` as the event id.
+fn change_event(change: &Change) -> Event {
+    let event = Event::default()
+        .id(change.seq.to_string())
+        .event("change");
+    match serde_json::to_string(change) {
+        Ok(data) => event.data(data),
+        Err(err) => event.event("error").data(err.to_string()),
+    }
+}
+
+/// Tells the client how many changes it missed, either because it was too slow or they are no longer logged.
+fn lagged_event(missed: u64) -> Event {
+    Event::default()
+        .event("lagged")
+        .data(serde_json::json!({ "missed": missed }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+
+    fn change(seq: u64, id: u32, deleted: bool) -> Change {
+        let deleted_at = if deleted { serde_json::json!("2026-10-18 12:00:00") } else { serde_json::Value::Null };
+        let row = serde_json::json!({ "id": id, "name": "Ann", "deleted_at": deleted_at });
+        let mut change = Change::new("user", id, "update", row.as_object().cloned());
+        change.seq = seq;
+        change
+    }
+
+    fn subscription(id: Option<u32>) -> Subscription {
+        Subscription { table: Some("user".to_string()), id }
+    }
+
+    fn admin() -> Option<Actor> {
+        Some(Actor { name: "test".to_string(), role: Role::Admin })
+    }
+
+    #[test]
+    fn replay_follows_the_read_rules() {
+        let replay = vec![change(4, 1, false), change(5, 2, true), change(6, 1, true)];
+        let events: Vec<String> = replay_events(&replay, 3, &subscription(Some(1)), &None).iter().map(Event::to_string).collect();
+        assert_eq!(events.len(), 3);
+        assert!(events[0].contains("event:lagged") && events[0].contains(r#"{"missed":3}"#));
+        assert!(events[1].contains("id:4") && events[1].contains(r#""row":{"#));
+        // Soft-deleted rows are only sent to admins
+        assert!(events[2].contains("id:6") && !events[2].contains(r#""row""#));
+
+        let events: Vec<String> = replay_events(&replay, 0, &subscription(None), &admin()).iter().map(Event::to_string).collect();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.contains(r#""row":{"#)));
+    }
+
+    #[tokio::test]
+    async fn live_events_follow_the_read_rules() {
+        let (sender, receiver) = broadcast::channel(4);
+        let mut events = Box::pin(live_events(receiver, subscription(Some(2)), None));
+        sender.send(change(1, 1, false)).unwrap();
+        sender.send(change(2, 2, false)).unwrap();
+        sender.send(change(3, 2, true)).unwrap();
+
+        let event = events.next().await.expect("the matching change should be sent").to_string();
+        assert!(event.contains("id:2") && event.contains(r#""row":{"#));
+        let event = events.next().await.expect("the deleted change should be sent").to_string();
+        assert!(event.contains("id:3") && !event.contains(r#""row""#));
+    }
+}
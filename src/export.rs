@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use serde_json::Value;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::DB_DESIGN;
+use crate::AppError;
+use crate::Check;
+use crate::db;
+use crate::db::Row;
+use crate::list::ListQuery;
+use crate::list::parse_list_query;
+use crate::routes::respond_stream;
+use crate::auth::Actor;
+use crate::auth::with_actor;
+
+/// The media type of CSV exports.
+const CSV_MIME: &str = "text/csv; charset=utf-8";
+
+// GET <domain>/user/export.csv
+/// A function that returns a warp route for downloading users as CSV.
+///
+/// The same filters as `list_user` can be used, but every matching user is exported unless a `limit` is given.
+/// `?fields=name,type` picks the columns, in that order.
+/// Rows are streamed from the database, so large exports are not held in memory.
+pub(crate) fn export_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / "export.csv")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(export_extract)
+        .and_then(export_success)
+}
+
+/// Checks the query parameters against the table's fields.
+async fn export_extract(query: HashMap<String, String>, actor: Option<Actor>) -> Result<ListQuery, warp::reject::Rejection> {
+    Ok(parse_list_query("user", query, &actor)?)
+}
+
+/// Streams the matching users as CSV, with a header row of column names.
+async fn export_success(query: ListQuery) -> Result<impl Reply, Rejection> {
+    let columns = match query.fields {
+        Some(fields) => fields,
+        None => export_columns("user")?,
+    };
+    let header = csv_line(columns.iter().map(String::as_str))?;
+    let rows = db::stream_rows("user", Some(&columns), query.filters, query.include_deleted, query.limit, query.offset)?;
+
+    respond_stream(rows, CSV_MIME, header, Box::new(move |row: &Row| {
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|column| csv_cell(row.get(column).unwrap_or(&Value::Null)))
+            .collect();
+        csv_line(cells.iter().map(String::as_str))
+    })).await
+}
+
+/// Returns the columns of a table's export: the primary key, the other fields by name,
+/// and `deleted_at` if the table soft deletes.
+pub(crate) fn export_columns(table: &str) -> Result<Vec<String>, AppError> {
+    let primary_key = db::primary_key(table);
+    let mut columns: Vec<String> = DB_DESIGN.tables.get(table).check()?.fields
+        .keys()
+        .filter(|name| **name != primary_key)
+        .cloned()
+        .collect();
+    columns.sort();
+    columns.insert(0, primary_key);
+    if db::soft_deletes(table) {
+        columns.push(db::SOFT_DELETE_COLUMN.to_string());
+    }
+    Ok(columns)
+}
+
+/// Writes one CSV line, quoting cells where needed.
+pub(crate) fn csv_line<'a>(cells: impl Iterator<Item = &'a str>) -> Result<Vec<u8>, AppError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(cells)?;
+    writer.into_inner().map_err(|err| AppError::from(err.into_error()))
+}
+
+/// Formats a JSON value as a CSV cell, leaving NULL empty.
+pub(crate) fn csv_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.to_string(),
+        other => other.to_string(),
+    }
+}
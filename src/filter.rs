@@ -0,0 +1,561 @@
+use serde_json::Value;
+use rustract::types::DataType;
+
+use crate::DB_DESIGN;
+use crate::ErrorType;
+use crate::AppError;
+use crate::Check;
+use crate::db;
+use crate::db::Condition;
+use crate::db::SqlValue;
+
+/// The longest filter accepted, in characters.
+const MAX_LENGTH: usize = 2000;
+
+/// How deeply parentheses and `not` may be nested.
+const MAX_DEPTH: usize = 32;
+
+/// The most values an `in (...)` list may have.
+const MAX_IN_VALUES: usize = 100;
+
+/// The comparison operators, longest first so that `<=` is not read as `<`.
+const OPERATORS: [&str; 7] = ["<=", ">=", "!=", "<>", "=", "<", ">"];
+
+/// A token of a filter, with the 1-based column it starts at for error messages.
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    /// A field name or a keyword, such as `and` or `null`.
+    Word(String),
+    /// A quoted string, with `''` unescaped.
+    Text(String),
+    Number(serde_json::Number),
+    Operator(&'static str),
+    Open,
+    Close,
+    Comma,
+    End,
+}
+
+impl Token {
+    /// Describes the token for error messages.
+    fn describe(&self) -> String {
+        match &self.kind {
+            TokenKind::Word(word) => format!("\"{}\"", word),
+            TokenKind::Text(text) => format!("'{}'", text.replace('\'', "''")),
+            TokenKind::Number(number) => number.to_string(),
+            TokenKind::Operator(operator) => format!("\"{}\"", operator),
+            TokenKind::Open => "\"(\"".to_string(),
+            TokenKind::Close => "\")\"".to_string(),
+            TokenKind::Comma => "\",\"".to_string(),
+            TokenKind::End => "the end of the filter".to_string(),
+        }
+    }
+
+    /// Returns whether the token is the given keyword, ignoring case.
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.kind, TokenKind::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+}
+
+/// A field named in a filter.
+#[derive(Debug, Clone, PartialEq)]
+struct Operand {
+    name: String,
+    token: Token,
+}
+
+/// A value written in a filter, before it is checked against the field it is compared with.
+#[derive(Debug, Clone, PartialEq)]
+struct Literal {
+    value: Value,
+    token: Token,
+}
+
+/// A parsed filter expression.
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// `field <operator> value`
+    Compare(Operand, &'static str, Literal),
+    /// `field [not] in (value, ...)`
+    In(Operand, bool, Vec<Literal>),
+    /// `field is [not] null`
+    Null(Operand, bool),
+    /// `field [not] like 'pattern'`
+    Like(Operand, bool, Literal),
+}
+
+/// Parses a `filter` parameter into a condition on a table's rows, such as
+/// `type in ('Admin', 'Mod') and registered >= '2023-01-01'`.
+///
+/// Values are checked against the types of the fields they are compared with, and bound as parameters,
+/// so they never become part of the SQL. Errors are `400 Bad Request` naming the column of the offending token.
+pub(crate) fn parse_filter(table: &str, filter: &str) -> Result<Condition, AppError> {
+    if filter.chars().count() > MAX_LENGTH {
+        return Err(filter_error(format!("filter can be at most {} characters long", MAX_LENGTH)));
+    }
+    let tokens = tokenize(filter)?;
+    let mut parser = Parser { tokens, position: 0, depth: 0 };
+    let expr = parser.parse_or()?;
+    let next = parser.peek();
+    if next.kind != TokenKind::End {
+        return Err(token_error(next, "expected \"and\", \"or\" or the end of the filter"));
+    }
+
+    let mut condition = Condition { sql: String::new(), values: Vec::new() };
+    compile(table, &expr, &mut condition)?;
+    // Wrapped so that a top-level `or` stays separate from the other conditions
+    condition.sql = format!("({})", condition.sql);
+    Ok(condition)
+}
+
+/// An error about the filter as a whole.
+fn filter_error(message: String) -> AppError {
+    AppError {
+        err_type: ErrorType::BadRequest,
+        message,
+        field: Some("filter".to_string()),
+    }
+}
+
+/// An error pointing at a token of the filter.
+fn token_error(token: &Token, message: &str) -> AppError {
+    filter_error(format!("filter is invalid at column {} ({}): {}", token.column, token.describe(), message))
+}
+
+/// Splits a filter into tokens, ending with `TokenKind::End`.
+fn tokenize(filter: &str) -> Result<Vec<Token>, AppError> {
+    let chars: Vec<char> = filter.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let start = index;
+        let column = start + 1;
+        let c = chars[index];
+        let kind = if c.is_whitespace() {
+            index += 1;
+            continue;
+        } else if c == '(' {
+            index += 1;
+            TokenKind::Open
+        } else if c == ')' {
+            index += 1;
+            TokenKind::Close
+        } else if c == ',' {
+            index += 1;
+            TokenKind::Comma
+        } else if c == '\'' {
+            // Strings are single-quoted like in SQL, with '' for a quote
+            let mut text = String::new();
+            index += 1;
+            loop {
+                match chars.get(index) {
+                    Some('\'') if chars.get(index + 1) == Some(&'\'') => {
+                        text.push('\'');
+                        index += 2;
+                    },
+                    Some('\'') => {
+                        index += 1;
+                        break;
+                    },
+                    Some(c) => {
+                        text.push(*c);
+                        index += 1;
+                    },
+                    None => return Err(filter_error(format!("filter is invalid at column {}: the string is never closed with '", column))),
+                }
+            }
+            TokenKind::Text(text)
+        } else if c.is_ascii_digit() || (c == '-' && chars.get(index + 1).is_some_and(char::is_ascii_digit)) {
+            index += 1;
+            while chars.get(index).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '+' || *c == '-') {
+                // Signs are only part of a number in an exponent
+                if (chars[index] == '+' || chars[index] == '-') && !matches!(chars[index - 1], 'e' | 'E') {
+                    break;
+                }
+                index += 1;
+            }
+            let text: String = chars[start..index].iter().collect();
+            match serde_json::from_str::<Value>(&text) {
+                Ok(Value::Number(number)) => TokenKind::Number(number),
+                _ => return Err(filter_error(format!("filter is invalid at column {} (\"{}\"): this is not a number", column, text))),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            while chars.get(index).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+                index += 1;
+            }
+            TokenKind::Word(chars[start..index].iter().collect())
+        } else {
+            let rest: String = chars[start..chars.len().min(start + 2)].iter().collect();
+            match OPERATORS.iter().find(|operator| rest.starts_with(**operator)) {
+                Some(operator) => {
+                    index += operator.len();
+                    TokenKind::Operator(operator)
+                },
+                None => return Err(filter_error(format!("filter is invalid at column {} (\"{}\"): unexpected character", column, c))),
+            }
+        };
+        tokens.push(Token { kind, column });
+    }
+
+    tokens.push(Token { kind: TokenKind::End, column: chars.len() + 1 });
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the tokens of a filter.
+///
+/// `and` binds tighter than `or`, and `not` tighter than both, like in SQL.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        // The last token is always `End`, which is never consumed
+        &self.tokens[self.position.min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.peek().clone();
+        if token.kind != TokenKind::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    /// Consumes the next token if it is the given keyword.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek().is_keyword(keyword);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    /// Consumes the next token, which must be of the given kind.
+    fn expect(&mut self, kind: TokenKind, expected: &str) -> Result<Token, AppError> {
+        let token = self.next();
+        if token.kind != kind {
+            return Err(token_error(&token, &format!("expected {}", expected)));
+        }
+        Ok(token)
+    }
+
+    /// Counts a level of nesting, so that deep filters cannot overflow the stack.
+    fn nest(&mut self) -> Result<(), AppError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(token_error(self.peek(), &format!("filters can be nested at most {} levels deep", MAX_DEPTH)));
+        }
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.parse_and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.parse_not()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, AppError> {
+        if self.keyword("not") {
+            self.nest()?;
+            let expr = Expr::Not(Box::new(self.parse_not()?));
+            self.depth -= 1;
+            return Ok(expr);
+        }
+        if self.peek().kind == TokenKind::Open {
+            self.next();
+            self.nest()?;
+            let expr = self.parse_or()?;
+            self.depth -= 1;
+            self.expect(TokenKind::Close, "\")\"")?;
+            return Ok(expr);
+        }
+        self.parse_comparison()
+    }
+
+    /// Parses a condition on one field.
+    fn parse_comparison(&mut self) -> Result<Expr, AppError> {
+        let token = self.next();
+        let operand = match &token.kind {
+            TokenKind::Word(name) if !is_reserved(name) => Operand { name: name.to_string(), token: token.clone() },
+            _ => return Err(token_error(&token, "expected a field name")),
+        };
+
+        if self.keyword("is") {
+            let negated = self.keyword("not");
+            let null = self.next();
+            if !null.is_keyword("null") {
+                return Err(token_error(&null, "expected \"null\" after \"is\""));
+            }
+            return Ok(Expr::Null(operand, negated));
+        }
+
+        let negated = self.keyword("not");
+        if self.keyword("in") {
+            self.expect(TokenKind::Open, "\"(\" after \"in\"")?;
+            let mut values = vec![self.parse_literal()?];
+            while self.peek().kind == TokenKind::Comma {
+                self.next();
+                values.push(self.parse_literal()?);
+                if values.len() > MAX_IN_VALUES {
+                    return Err(token_error(&values[values.len() - 1].token, &format!("\"in\" can have at most {} values", MAX_IN_VALUES)));
+                }
+            }
+            self.expect(TokenKind::Close, "\",\" or \")\"")?;
+            return Ok(Expr::In(operand, negated, values));
+        }
+        if self.keyword("like") {
+            return Ok(Expr::Like(operand, negated, self.parse_literal()?));
+        }
+        if negated {
+            return Err(token_error(self.peek(), "expected \"in\" or \"like\" after \"not\""));
+        }
+
+        let operator = self.next();
+        match operator.kind {
+            TokenKind::Operator(operator) => Ok(Expr::Compare(operand, operator, self.parse_literal()?)),
+            _ => Err(token_error(&operator, "expected an operator: =, !=, <, <=, >, >=, in, like or is")),
+        }
+    }
+
+    /// Parses a string, number or boolean.
+    fn parse_literal(&mut self) -> Result<Literal, AppError> {
+        let token = self.next();
+        let value = match &token.kind {
+            TokenKind::Text(text) => Value::String(text.to_string()),
+            TokenKind::Number(number) => Value::Number(number.clone()),
+            TokenKind::Word(word) if word.eq_ignore_ascii_case("true") => Value::Bool(true),
+            TokenKind::Word(word) if word.eq_ignore_ascii_case("false") => Value::Bool(false),
+            TokenKind::Word(word) if word.eq_ignore_ascii_case("null") =>
+                return Err(token_error(&token, "null cannot be compared, use \"is null\" or \"is not null\"")),
+            _ => return Err(token_error(&token, "expected a value: a 'string', a number, true or false")),
+        };
+        Ok(Literal { value, token })
+    }
+}
+
+/// Returns whether a word is a keyword, which cannot be used as a field name.
+fn is_reserved(word: &str) -> bool {
+    ["and", "or", "not", "in", "is", "null", "like", "true", "false"]
+        .iter()
+        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+/// Type-checks an expression against the table and appends its SQL and values to the condition.
+fn compile(table: &str, expr: &Expr, condition: &mut Condition) -> Result<(), AppError> {
+    match expr {
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            condition.sql.push('(');
+            compile(table, left, condition)?;
+            condition.sql.push_str(if matches!(expr, Expr::And(..)) { " AND " } else { " OR " });
+            compile(table, right, condition)?;
+            condition.sql.push(')');
+        },
+        Expr::Not(inner) => {
+            condition.sql.push_str("NOT (");
+            compile(table, inner, condition)?;
+            condition.sql.push(')');
+        },
+        Expr::Compare(operand, operator, literal) => {
+            let column = check_field(table, operand)?;
+            condition.values.push(check_value(table, operand, literal)?);
+            // `<>` and `!=` mean the same in MySQL
+            condition.sql.push_str(&format!("`{}` {} ?", column, if *operator == "<>" { "!=" } else { operator }));
+        },
+        Expr::In(operand, negated, literals) => {
+            let column = check_field(table, operand)?;
+            for literal in literals {
+                condition.values.push(check_value(table, operand, literal)?);
+            }
+            condition.sql.push_str(&format!(
+                "`{}` {}IN ({})",
+                column,
+                if *negated { "NOT " } else { "" },
+                vec!["?"; literals.len()].join(", ")
+            ));
+        },
+        Expr::Null(operand, negated) => {
+            let column = check_field(table, operand)?;
+            condition.sql.push_str(&format!("`{}` IS {}NULL", column, if *negated { "NOT " } else { "" }));
+        },
+        Expr::Like(operand, negated, literal) => {
+            let column = check_field(table, operand)?;
+            let is_text = column == db::SOFT_DELETE_COLUMN
+                || DB_DESIGN.tables.get(table).check()?.fields.get(&column).is_some_and(|field| field.datatype == DataType::String);
+            if !is_text {
+                return Err(token_error(&operand.token, &format!("like can only be used with text fields, and {} is not one", column)));
+            }
+            // Patterns are not checked like values, since `%` and `_` rarely make a valid value
+            match &literal.value {
+                Value::String(pattern) => condition.values.push(SqlValue::String(pattern.to_string())),
+                _ => return Err(token_error(&literal.token, "like should be followed by a 'pattern'")),
+            }
+            condition.sql.push_str(&format!("`{}` {}LIKE ?", column, if *negated { "NOT " } else { "" }));
+        },
+    }
+    Ok(())
+}
+
+/// Checks that a field exists in the table, or is `deleted_at` in a table that soft deletes, and returns its column.
+fn check_field(table: &str, operand: &Operand) -> Result<String, AppError> {
+    let design = DB_DESIGN.tables.get(table).check()?;
+    let known = design.fields.contains_key(&operand.name)
+        || (operand.name == db::SOFT_DELETE_COLUMN && db::soft_deletes(table));
+    if !known {
+        return Err(token_error(&operand.token, &format!("{} is not a field of {}", operand.name, table)));
+    }
+    Ok(operand.name.to_string())
+}
+
+/// Checks a value against the type of the field it is compared with, like a field of a request body.
+fn check_value(table: &str, operand: &Operand, literal: &Literal) -> Result<SqlValue, AppError> {
+    let design = DB_DESIGN.tables.get(table).check()?;
+    let field = match design.fields.get(&operand.name) {
+        Some(field) => field,
+        // `deleted_at` is not in the design, and MySQL reads times written as strings
+        None => return match &literal.value {
+            Value::String(time) => Ok(SqlValue::String(time.to_string())),
+            _ => Err(token_error(&literal.token, &format!("{} should be compared with a 'time'", operand.name))),
+        },
+    };
+
+    match field.extract(&literal.value) {
+        Ok(value) => db::sql_value(field, value),
+        Err(error) => Err(token_error(
+            &literal.token,
+            &format!("this is not a valid value for field {}: {}", field.field_design_title, error)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<SqlValue> {
+        values.iter().map(|value| SqlValue::String(value.to_string())).collect()
+    }
+
+    fn error(filter: &str) -> String {
+        parse_filter("user", filter).expect_err("the filter should be invalid").message
+    }
+
+    fn kinds(filter: &str) -> Vec<TokenKind> {
+        tokenize(filter).expect("the filter should tokenize").into_iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let condition = parse_filter("user", "name = 'a' or name = 'b' and email = 'c'").unwrap();
+        assert_eq!(condition.sql, "((`name` = ? OR (`name` = ? AND `email` = ?)))");
+        assert_eq!(condition.values, strings(&["a", "b", "c"]));
+
+        let condition = parse_filter("user", "(name = 'a' or name = 'b') and not email = 'c'").unwrap();
+        assert_eq!(condition.sql, "(((`name` = ? OR `name` = ?) AND NOT (`email` = ?)))");
+    }
+
+    #[test]
+    fn quotes_are_escaped_by_doubling() {
+        assert_eq!(kinds("'O''Brien'"), vec![TokenKind::Text("O'Brien".to_string()), TokenKind::End]);
+        assert_eq!(kinds("''''"), vec![TokenKind::Text("'".to_string()), TokenKind::End]);
+        assert_eq!(kinds("''"), vec![TokenKind::Text(String::new()), TokenKind::End]);
+
+        let condition = parse_filter("user", "name = 'O''Brien'").unwrap();
+        assert_eq!(condition.values, strings(&["O'Brien"]));
+    }
+
+    #[test]
+    fn numbers_can_be_negative_or_have_exponents() {
+        let number = |text: &str| TokenKind::Number(serde_json::from_str(text).unwrap());
+        assert_eq!(kinds("-5"), vec![number("-5"), TokenKind::End]);
+        assert_eq!(kinds("1e3 2.5E-2 1e+2"), vec![number("1e3"), number("2.5E-2"), number("1e+2"), TokenKind::End]);
+        // A sign outside an exponent starts the next token
+        assert_eq!(kinds("5-3"), vec![number("5"), number("-3"), TokenKind::End]);
+        assert_eq!(kinds("id>-1"), vec![TokenKind::Word("id".to_string()), TokenKind::Operator(">"), number("-1"), TokenKind::End]);
+        assert!(error("id = 1e").contains("column 6 (\"1e\"): this is not a number"));
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}name = 'a'{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_filter("user", &nested(MAX_DEPTH)).is_ok());
+        assert!(error(&nested(MAX_DEPTH + 1)).contains(&format!("nested at most {} levels deep", MAX_DEPTH)));
+
+        let negated = |depth: usize| format!("{}name = 'a'", "not ".repeat(depth));
+        assert!(parse_filter("user", &negated(MAX_DEPTH)).is_ok());
+        assert!(error(&negated(MAX_DEPTH + 1)).contains(&format!("nested at most {} levels deep", MAX_DEPTH)));
+    }
+
+    #[test]
+    fn in_values_are_limited() {
+        let values = |count: usize| format!("name in ({})", vec!["'a'"; count].join(", "));
+        assert_eq!(parse_filter("user", &values(MAX_IN_VALUES)).unwrap().values.len(), MAX_IN_VALUES);
+        assert!(error(&values(MAX_IN_VALUES + 1)).contains(&format!("\"in\" can have at most {} values", MAX_IN_VALUES)));
+    }
+
+    #[test]
+    fn errors_name_the_column() {
+        assert_eq!(error("name = 'a' and"), "filter is invalid at column 15 (the end of the filter): expected a field name");
+        assert_eq!(error("name == 'a'"), "filter is invalid at column 7 (\"=\"): expected a value: a 'string', a number, true or false");
+        assert_eq!(error("nme = 'a'"), "filter is invalid at column 1 (\"nme\"): nme is not a field of user");
+        assert_eq!(error("(name = 'a'"), "filter is invalid at column 12 (the end of the filter): expected \")\"");
+        assert_eq!(error("name = 'a' name"), "filter is invalid at column 12 (\"name\"): expected \"and\", \"or\" or the end of the filter");
+        assert_eq!(error("name = 'abc"), "filter is invalid at column 8: the string is never closed with '");
+        assert_eq!(error("name ~ 'a'"), "filter is invalid at column 6 (\"~\"): unexpected character");
+        assert_eq!(error("name = null"), "filter is invalid at column 8 (\"null\"): null cannot be compared, use \"is null\" or \"is not null\"");
+        // Columns count characters, not bytes
+        assert_eq!(error("name = 'é' or"), "filter is invalid at column 14 (the end of the filter): expected a field name");
+    }
+
+    #[test]
+    fn conditions_compile_to_placeholders() {
+        let condition = parse_filter("user", "type in ('Admin', 'Mod')").unwrap();
+        assert_eq!(condition.sql, "(`type` IN (?, ?))");
+        assert_eq!(condition.values, strings(&["Admin", "Mod"]));
+
+        let condition = parse_filter("user", "type NOT IN ('Basic')").unwrap();
+        assert_eq!(condition.sql, "(`type` NOT IN (?))");
+        assert_eq!(condition.values, strings(&["Basic"]));
+
+        let condition = parse_filter("user", "email not like '%@example.com'").unwrap();
+        assert_eq!(condition.sql, "(`email` NOT LIKE ?)");
+        assert_eq!(condition.values, strings(&["%@example.com"]));
+
+        let condition = parse_filter("user", "registered is not null").unwrap();
+        assert_eq!(condition.sql, "(`registered` IS NOT NULL)");
+        assert!(condition.values.is_empty());
+
+        let condition = parse_filter("user", "registered is null and name <> 'a'").unwrap();
+        assert_eq!(condition.sql, "((`registered` IS NULL AND `name` != ?))");
+        assert_eq!(condition.values, strings(&["a"]));
+    }
+
+    #[test]
+    fn values_are_checked_against_fields() {
+        assert!(error("type = 'Owner'").starts_with("filter is invalid at column 8 ('Owner'): this is not a valid value for field type"));
+        assert_eq!(error("id like '1%'"), "filter is invalid at column 1 (\"id\"): like can only be used with text fields, and id is not one");
+        assert_eq!(error("name like 1"), "filter is invalid at column 11 (1): like should be followed by a 'pattern'");
+    }
+}
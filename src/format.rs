@@ -0,0 +1,253 @@
+use warp::Rejection;
+use warp::Filter;
+use warp::hyper::body::Bytes;
+use warp::hyper::header;
+
+use crate::AppError;
+use crate::ErrorType;
+
+/// A serialization format that request and response bodies can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    /// Returns the media type sent in the `Content-Type` header for this format.
+    pub(crate) fn mime(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    /// Finds the format matching a media type, ignoring any parameters such as `charset`.
+    ///
+    /// Wildcards are answered with JSON.
+    fn from_mime(mime: &str) -> Option<Format> {
+        match essence(mime).as_str() {
+            "application/json" | "application/problem+json" | "application/*" | "*/*" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MessagePack),
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Serializes a value into a response body.
+    pub(crate) fn encode<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, AppError> {
+        let encoded = match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes)
+                    .map(|_| bytes)
+                    .map_err(|e| e.to_string())
+            },
+        };
+        encoded.map_err(|message| AppError::new(
+            ErrorType::Internal,
+            format!("err: failed to encode response as {}: {}", self.mime(), message)
+        ))
+    }
+
+    /// Deserializes a request body into the JSON representation used by the route handlers.
+    pub(crate) fn decode(&self, bytes: &[u8]) -> Result<serde_json::Value, AppError> {
+        let decoded = match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string()),
+        };
+        decoded.map_err(|message| AppError::new(
+            ErrorType::BadRequest,
+            format!("Invalid Body: failed to decode {}: {}", self.mime(), message)
+        ))
+    }
+}
+
+/// The media type of newline-delimited JSON, which collection routes can stream.
+pub(crate) const NDJSON_MIME: &str = "application/x-ndjson";
+
+/// How a collection route replies, see `negotiate_list`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ListFormat {
+    /// One JSON object per line, streamed as rows are read.
+    Ndjson,
+    /// A single array in one of the body formats.
+    Body(Format),
+}
+
+/// Picks the response format from an `Accept` header.
+///
+/// Media ranges are tried in order of their `q` value, and a missing header means JSON.
+/// Errors with `406 Not Acceptable` if none of the ranges are supported.
+pub(crate) fn negotiate(accept: Option<&str>) -> Result<Format, AppError> {
+    let accept = match accept {
+        Some(accept) if !accept.trim().is_empty() => accept,
+        _ => return Ok(Format::Json),
+    };
+
+    preferred_ranges(accept)
+        .into_iter()
+        .find_map(Format::from_mime)
+        .ok_or_else(|| AppError::new(
+            ErrorType::NotAcceptable,
+            format!("Not Acceptable: none of \"{}\" are supported (try application/json, application/msgpack or application/cbor)", accept)
+        ))
+}
+
+/// Picks the response format of a collection route, which can also stream NDJSON.
+///
+/// NDJSON is only used if the client asks for it by name, so wildcards still get a JSON array.
+pub(crate) fn negotiate_list(accept: Option<&str>) -> Result<ListFormat, AppError> {
+    let accept = match accept {
+        Some(accept) if !accept.trim().is_empty() => accept,
+        _ => return Ok(ListFormat::Body(Format::Json)),
+    };
+
+    preferred_ranges(accept)
+        .into_iter()
+        .find_map(|range| {
+            if essence(range) == NDJSON_MIME {
+                Some(ListFormat::Ndjson)
+            } else {
+                Format::from_mime(range).map(ListFormat::Body)
+            }
+        })
+        .ok_or_else(|| AppError::new(
+            ErrorType::NotAcceptable,
+            format!("Not Acceptable: none of \"{}\" are supported (try application/json, application/x-ndjson, application/msgpack or application/cbor)", accept)
+        ))
+}
+
+/// Returns the media type of a `Content-Type` or media range in lowercase, without parameters such as `charset`.
+pub(crate) fn essence(mime: &str) -> String {
+    mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+/// Returns the media ranges of an `Accept` header with a non-zero `q` value, most preferred first.
+fn preferred_ranges(accept: &str) -> Vec<&str> {
+    // Each range is paired with its quality so that the preferred ranges are tried first
+    let mut ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .map(|range| {
+            let quality = range
+                .split(';')
+                .skip(1)
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (range, quality)
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+/// Extracts the response format requested by the client.
+pub(crate) fn with_format() -> impl Filter<Extract = (Format,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(header::ACCEPT.as_str())
+        .and_then(|accept: Option<String>| async move {
+            negotiate(accept.as_deref()).map_err(AppError::into_warp)
+        })
+}
+
+/// Extracts the response format requested by the client for a collection route.
+pub(crate) fn with_list_format() -> impl Filter<Extract = (ListFormat,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(header::ACCEPT.as_str())
+        .and_then(|accept: Option<String>| async move {
+            negotiate_list(accept.as_deref()).map_err(AppError::into_warp)
+        })
+}
+
+/// Rejects the request early if the client does not accept any supported format.
+///
+/// Routes that change data use this before doing any work, then call `with_format` when replying.
+pub(crate) fn check_accept() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_format().map(|_| ()).untuple_one()
+}
+
+/// Decodes a request body using the format named by its `Content-Type`.
+pub(crate) async fn decode_body(content_type: Option<String>, bytes: Bytes) -> Result<serde_json::Value, Rejection> {
+    let format = match content_type {
+        None => Format::Json,
+        Some(content_type) => match Format::from_mime(&content_type) {
+            Some(format) => format,
+            None => return Err(AppError::new(
+                ErrorType::UnsupportedMediaType,
+                format!("Unsupported Media Type: \"{}\" (try application/json, application/msgpack or application/cbor)", content_type)
+            ).into_warp()),
+        },
+    };
+    format.decode(&bytes).map_err(AppError::into_warp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    fn status(rejection: Rejection) -> StatusCode {
+        rejection.find::<AppError>().map_or(StatusCode::INTERNAL_SERVER_ERROR, AppError::to_http_status)
+    }
+
+    #[test]
+    fn formats_are_picked_by_quality() {
+        assert_eq!(negotiate(None).unwrap(), Format::Json);
+        assert_eq!(negotiate(Some(" ")).unwrap(), Format::Json);
+        assert_eq!(negotiate(Some("application/cbor")).unwrap(), Format::Cbor);
+        assert_eq!(negotiate(Some("Application/MsgPack; charset=binary")).unwrap(), Format::MessagePack);
+        assert_eq!(negotiate(Some("application/json;q=0.5, application/cbor;q=0.9")).unwrap(), Format::Cbor);
+        // Ranges with the same quality keep their order
+        assert_eq!(negotiate(Some("application/msgpack, application/cbor")).unwrap(), Format::MessagePack);
+        assert_eq!(negotiate(Some("text/html, */*;q=0.1")).unwrap(), Format::Json);
+        assert_eq!(negotiate(Some("application/cbor;q=0, application/*")).unwrap(), Format::Json);
+    }
+
+    #[test]
+    fn unsupported_formats_are_not_acceptable() {
+        let err = negotiate(Some("text/html")).unwrap_err();
+        assert_eq!(err.to_http_status(), StatusCode::NOT_ACCEPTABLE);
+        // q=0 means the client refuses the format
+        let err = negotiate(Some("application/json;q=0")).unwrap_err();
+        assert_eq!(err.to_http_status(), StatusCode::NOT_ACCEPTABLE);
+        let err = negotiate_list(Some("text/csv")).unwrap_err();
+        assert_eq!(err.to_http_status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[test]
+    fn lists_only_stream_when_asked_by_name() {
+        assert_eq!(negotiate_list(None).unwrap(), ListFormat::Body(Format::Json));
+        assert_eq!(negotiate_list(Some("*/*")).unwrap(), ListFormat::Body(Format::Json));
+        assert_eq!(negotiate_list(Some("application/x-ndjson")).unwrap(), ListFormat::Ndjson);
+        assert_eq!(negotiate_list(Some("application/json;q=0.8, application/x-ndjson")).unwrap(), ListFormat::Ndjson);
+        assert_eq!(negotiate_list(Some("application/x-ndjson;q=0, application/cbor")).unwrap(), ListFormat::Body(Format::Cbor));
+    }
+
+    #[tokio::test]
+    async fn bodies_are_decoded_by_content_type() {
+        let body = json!({ "name": "Ann", "type": "Admin", "tags": [1, 2] });
+        for format in [Format::Json, Format::MessagePack, Format::Cbor] {
+            let bytes = Bytes::from(format.encode(&body).unwrap());
+            let content_type = format!("{}; charset=utf-8", format.mime().to_uppercase());
+            assert_eq!(decode_body(Some(content_type), bytes).await.unwrap(), body);
+        }
+        // A missing Content-Type is read as JSON
+        assert_eq!(decode_body(None, Bytes::from(r#"{"a":1}"#)).await.unwrap(), json!({ "a": 1 }));
+    }
+
+    #[tokio::test]
+    async fn undecodable_bodies_are_rejected() {
+        let rejection = decode_body(Some("text/plain".to_string()), Bytes::from("name=Ann")).await.unwrap_err();
+        assert_eq!(status(rejection), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let rejection = decode_body(Some("application/cbor".to_string()), Bytes::from("{")).await.unwrap_err();
+        assert_eq!(status(rejection), StatusCode::BAD_REQUEST);
+        let rejection = decode_body(None, Bytes::from("{")).await.unwrap_err();
+        assert_eq!(status(rejection), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,40 +1,87 @@
 use warp::Rejection;
 use warp::Reply;
 use warp::Filter;
+use warp::hyper::header;
 
+use crate::db;
+use crate::db::Row;
 use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::conditional::etag;
+use crate::conditional::weak_etag;
+use crate::conditional::is_not_modified;
+use crate::conditional::with_if_none_match;
+use crate::auth::with_include_deleted;
+use crate::list::with_fields;
+use crate::relations;
+use crate::relations::with_embed;
+use crate::cache;
+use crate::cache::CacheKey;
+use crate::cache::with_cache_key;
 
 // GET <domain>/user/#
 /// A function that returns a warp route for getting user info.
 /// 
+/// The reply includes an `ETag`, and `If-None-Match` is answered with `304 Not Modified`.
+/// Soft-deleted users are only returned to admins passing `?include_deleted=true`.
+/// `?fields=name,type` only reads and returns those fields, with a weak `ETag` that cannot be used for `If-Match`.
+/// `?embed=` inlines related rows of other tables under `_embedded`, see `relations::parse_embed`.
+/// Since the related rows can change without the user changing, these replies have no `ETag` and are never `304 Not Modified`.
+/// If the response cache is enabled, recent reads are answered from memory until a change is committed.
 /// Note that `warp::query` can be used for URL queries for more complicated data than `u32`.
 pub(crate) fn get_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("user" / u32)
         .and(warp::get())
+        .and(with_include_deleted())
+        .and(with_fields("user"))
+        .and(with_embed("user"))
+        .and(with_cache_key())
         .and_then(get_retrieve)
+        .and(with_if_none_match())
+        .and(with_format())
         .and_then(get_success)
 }
 
-/// Uses the id to create a GET query.
-async fn get_retrieve(id: u32) -> Result<String, warp::reject::Rejection> {
-    // An SQL query can be made here that safely updates any verified data
-    // You could also error if nothing but the id is included
-    println!(
-        "User #{}: <SQL-retrieved stuff>",
-        id
-    );
-
-    // The id is passed on for the hello world filter to consume
-    Ok(id.to_string())
+/// Uses the id to make an SQL SELECT query, reading only the requested fields and then the embedded rows.
+///
+/// Cached users are returned without touching the database.
+/// The user is returned with its `ETag`, which is weak if only some fields were read, or `None` if rows were embedded.
+async fn get_retrieve(id: u32, include_deleted: bool, fields: Option<Vec<String>>, embed: Vec<String>, key: CacheKey) -> Result<(Row, Option<String>), warp::reject::Rejection> {
+    let user = match cache::lookup(&key) {
+        Some(serde_json::Value::Object(user)) => user,
+        _ => {
+            let generation = cache::generation();
+            let mut conn = db::acquire().await?;
+            let mut user = db::fetch_fields(&mut conn, "user", fields.as_deref(), id, include_deleted).await?;
+            relations::embed(&mut conn, "user", id, &embed, include_deleted, &mut user).await?;
+            cache::store(key, generation, serde_json::Value::Object(user.clone()));
+            user
+        },
+    };
+    let tag = if !embed.is_empty() {
+        None
+    } else if fields.is_some() {
+        Some(weak_etag(&user))
+    } else {
+        Some(etag(&user))
+    };
+    Ok((user, tag))
 }
 
-/// Replies with a success code and GET-related message.
-async fn get_success(user_id: String) -> Result<impl Reply, Rejection> {
-    respond(
-        Ok(format!(
-            "Welcome, User #{}! If this was hooked up to a database, your information would be retrieved.",
-            user_id)
-        ),
-        warp::http::StatusCode::ACCEPTED
-    )
+/// Replies with the user, or with `304 Not Modified` if the client's copy is current.
+async fn get_success(req: (Row, Option<String>), if_none_match: Option<String>, format: Format) -> Result<warp::reply::Response, Rejection> {
+    let (user, tag) = req;
+    match tag {
+        Some(tag) if is_not_modified(&tag, &if_none_match) => {
+            let reply = warp::reply::with_status(warp::reply(), warp::http::StatusCode::NOT_MODIFIED);
+            Ok(warp::reply::with_header(reply, header::ETAG, tag).into_response())
+        },
+        Some(tag) => Ok(warp::reply::with_header(
+            respond(Ok(user), warp::http::StatusCode::OK, format)?,
+            header::ETAG,
+            tag
+        ).into_response()),
+        None => Ok(respond(Ok(user), warp::http::StatusCode::OK, format)?.into_response()),
+    }
 }
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use async_graphql::ErrorExtensions;
+use async_graphql::dynamic::Field;
+use async_graphql::dynamic::FieldFuture;
+use async_graphql::dynamic::FieldValue;
+use async_graphql::dynamic::InputObject;
+use async_graphql::dynamic::InputValue;
+use async_graphql::dynamic::Object;
+use async_graphql::dynamic::ResolverContext;
+use async_graphql::dynamic::Scalar;
+use async_graphql::dynamic::Schema;
+use async_graphql::dynamic::TypeRef;
+use lazy_static::lazy_static;
+use rustract::field::FieldDesign;
+use rustract::types::DataType;
+use serde_json::Value;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::DB_DESIGN;
+use crate::ErrorType;
+use crate::AppError;
+use crate::Check;
+use crate::debug_mode;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond;
+use crate::routes::with_body;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::schema::Variant;
+use crate::schema::schema_name;
+use crate::schema::sorted_fields;
+use crate::conditional::etag;
+use crate::auth::include_deleted;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
+use crate::list::DEFAULT_LIMIT;
+use crate::list::MAX_LIMIT;
+use crate::list::parse_list_query;
+use crate::post::create_row;
+use crate::post::extract_row;
+use crate::patch::extract_changes;
+use crate::patch::modify_row;
+use crate::delete::remove_row;
+
+/// The deepest a query may nest, so that a single request cannot do unbounded work.
+const MAX_DEPTH: usize = 16;
+
+/// The scalar used for JSON fields, which are passed through as they are.
+const JSON_SCALAR: &str = "JSON";
+
+// The schema is generated from `DB_DESIGN` on first use, and is read-only afterwards.
+lazy_static! {
+    static ref SCHEMA: Schema = gen_schema().expect("GraphQL schema should build from the database design");
+}
+
+// POST <domain>/graphql
+/// A function that returns a warp route for running GraphQL queries and mutations.
+///
+/// The body is a standard GraphQL request: `{ "query": "...", "variables": {...}, "operationName": "..." }`.
+/// Requests use the same bearer tokens as the REST routes, and mutations are recorded in the audit log.
+pub(crate) fn graphql() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("graphql")
+        .and(warp::post())
+        .and(check_accept())
+        .and(with_body())
+        .and(with_audit())
+        .and_then(graphql_retrieve)
+        .and(with_format())
+        .and_then(graphql_success)
+}
+
+/// Parses the request and runs it against the generated schema.
+///
+/// Errors inside the query are part of the GraphQL response, so only a malformed request is rejected.
+async fn graphql_retrieve(body: Value, context: AuditContext) -> Result<async_graphql::Response, warp::reject::Rejection> {
+    let request: async_graphql::Request = serde_json::from_value(body).map_err(|err| AppError::new(
+        ErrorType::BadRequest,
+        format!("failed to parse GraphQL request (err: {})", err)
+    ))?;
+    Ok(SCHEMA.execute(request.data(context)).await)
+}
+
+/// Replies with the GraphQL response, which carries its own `errors`.
+async fn graphql_success(response: async_graphql::Response, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(response),
+        StatusCode::OK,
+        format
+    )
+}
+
+/// Generates the schema: a row type, a filter and inputs for each table, with queries and mutations.
+fn gen_schema() -> Result<Schema, AppError> {
+    let mut query = Object::new("Query");
+    let mut mutation = Object::new("Mutation");
+    let mut builder = Schema::build("Query", Some("Mutation"), None)
+        .register(Scalar::new(JSON_SCALAR))
+        .limit_depth(MAX_DEPTH);
+
+    // Sorted so that the schema is the same on every start
+    let mut tables: Vec<&'static str> = DB_DESIGN.tables.keys().map(String::as_str).collect();
+    tables.sort_unstable();
+    for table in tables {
+        let design = DB_DESIGN.tables.get(table).check()?;
+        let fields = sorted_fields(design);
+        let primary_key = db::primary_key(table);
+
+        builder = builder
+            .register(row_type(table, &fields))
+            .register(input_type(&format!("{}Filter", schema_name(table, Variant::Row)), &fields, |_| false, None))
+            .register(input_type(&schema_name(table, Variant::Create), &fields, |field| field.required && !field.generated, None))
+            .register(input_type(&schema_name(table, Variant::Update), &fields, |_| false, Some(&primary_key)));
+        query = query
+            .field(get_field(table))
+            .field(list_field(table));
+        mutation = mutation
+            .field(create_field(table))
+            .field(update_field(table))
+            .field(delete_field(table));
+    }
+
+    builder
+        .register(query)
+        .register(mutation)
+        .finish()
+        .map_err(|err| AppError::new(ErrorType::Internal, format!("err: failed to build GraphQL schema: {}", err)))
+}
+
+/// Returns the GraphQL type of a field's values.
+fn field_type(field: &FieldDesign) -> &'static str {
+    match field.datatype {
+        DataType::Signed | DataType::Unsigned => TypeRef::INT,
+        DataType::Float => TypeRef::FLOAT,
+        DataType::Boolean => TypeRef::BOOLEAN,
+        DataType::Json => JSON_SCALAR,
+        _ => TypeRef::STRING,
+    }
+}
+
+/// Builds the object type of a table's rows, with an `_etag` for `ifMatch`.
+fn row_type(table: &str, fields: &[&FieldDesign]) -> Object {
+    let mut object = Object::new(schema_name(table, Variant::Row));
+    for field in fields {
+        let column = field.field_design_title.clone();
+        let ty = if field.required {
+            TypeRef::named_nn(field_type(field))
+        } else {
+            TypeRef::named(field_type(field))
+        };
+        object = object.field(Field::new(column.clone(), ty, move |ctx| {
+            let column = column.clone();
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                match row.get(&column) {
+                    Some(value) if !value.is_null() => Ok(Some(FieldValue::value(async_graphql::Value::from_json(value.clone())?))),
+                    _ => Ok(None),
+                }
+            })
+        }));
+    }
+    if db::soft_deletes(table) {
+        let column = db::SOFT_DELETE_COLUMN;
+        object = object.field(Field::new(column, TypeRef::named(TypeRef::STRING), move |ctx| {
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                Ok(row.get(column).and_then(Value::as_str).map(|time| FieldValue::value(time.to_string())))
+            })
+        }));
+    }
+    object.field(Field::new("_etag", TypeRef::named_nn(TypeRef::STRING), |ctx| {
+        FieldFuture::new(async move {
+            let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+            Ok(Some(FieldValue::value(etag(row))))
+        })
+    }))
+}
+
+/// Builds an input type with a table's fields, where `required` picks the non-null ones.
+///
+/// Like `extract_changes`, update inputs leave out the primary key, which is passed as `skip`.
+fn input_type(name: &str, fields: &[&FieldDesign], required: impl Fn(&FieldDesign) -> bool, skip: Option<&str>) -> InputObject {
+    let mut input = InputObject::new(name);
+    for field in fields {
+        if skip == Some(field.field_design_title.as_str()) {
+            continue;
+        }
+        let ty = if required(field) {
+            TypeRef::named_nn(field_type(field))
+        } else {
+            TypeRef::named(field_type(field))
+        };
+        input = input.field(InputValue::new(field.field_design_title.clone(), ty));
+    }
+    input
+}
+
+/// `user(id: Int!, include_deleted: Boolean): User`, which is null if the row does not exist.
+fn get_field(table: &'static str) -> Field {
+    Field::new(table, TypeRef::named(schema_name(table, Variant::Row)), move |ctx| {
+        FieldFuture::new(async move {
+            let context = ctx.data::<AuditContext>()?;
+            let id = id_argument(&ctx)?;
+            let include_deleted = include_deleted(bool_argument(&ctx, "include_deleted")?, &context.actor).map_err(graphql_error)?;
+
+            let mut conn = db::acquire().await.map_err(graphql_error)?;
+            let row = if include_deleted {
+                db::fetch_any_row(&mut conn, table, id, false).await
+            } else {
+                db::fetch_row(&mut conn, table, id, false).await
+            };
+            match row {
+                Ok(row) => Ok(Some(FieldValue::owned_any(row))),
+                Err(err) if matches!(err.err_type, ErrorType::NotFound) => Ok(None),
+                Err(err) => Err(graphql_error(err)),
+            }
+        })
+    })
+    .argument(InputValue::new("id", TypeRef::named_nn(TypeRef::INT)))
+    .argument(InputValue::new("include_deleted", TypeRef::named(TypeRef::BOOLEAN)))
+}
+
+/// `user_list(filter: UserFilter, limit: Int, offset: Int, include_deleted: Boolean): [User!]!`,
+/// filtered and paged like `GET /user`.
+fn list_field(table: &'static str) -> Field {
+    let row_name = schema_name(table, Variant::Row);
+    Field::new(format!("{}_list", table), TypeRef::named_nn_list_nn(&row_name), move |ctx| {
+        FieldFuture::new(async move {
+            let context = ctx.data::<AuditContext>()?;
+
+            // The arguments are turned into a REST query, so that they are checked the same way
+            let mut query = HashMap::new();
+            if let Some(filter) = ctx.args.get("filter") {
+                for (name, value) in filter.object()?.iter() {
+                    if !value.is_null() {
+                        query.insert(name.to_string(), query_value(value.as_value().clone().into_json()?));
+                    }
+                }
+            }
+            for name in ["limit", "offset"] {
+                if let Some(value) = ctx.args.get(name).filter(|value| !value.is_null()) {
+                    query.insert(name.to_string(), value.i64()?.to_string());
+                }
+            }
+            if let Some(include_deleted) = bool_argument(&ctx, "include_deleted")? {
+                query.insert("include_deleted".to_string(), include_deleted.to_string());
+            }
+
+            let list_query = parse_list_query(table, query, &context.actor).map_err(graphql_error)?;
+            let limit = list_query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+            let mut conn = db::acquire().await.map_err(graphql_error)?;
+            let rows = db::fetch_rows(&mut conn, table, list_query.fields.as_deref(), list_query.filters, list_query.include_deleted, limit, list_query.offset)
+                .await
+                .map_err(graphql_error)?;
+            Ok(Some(FieldValue::list(rows.into_iter().map(FieldValue::owned_any))))
+        })
+    })
+    .argument(InputValue::new("filter", TypeRef::named(format!("{}Filter", row_name))))
+    .argument(InputValue::new("limit", TypeRef::named(TypeRef::INT)))
+    .argument(InputValue::new("offset", TypeRef::named(TypeRef::INT)))
+    .argument(InputValue::new("include_deleted", TypeRef::named(TypeRef::BOOLEAN)))
+}
+
+/// `create_user(input: UserCreate!): User!`, validated like `POST /user`.
+fn create_field(table: &'static str) -> Field {
+    Field::new(format!("create_{}", table), TypeRef::named_nn(schema_name(table, Variant::Row)), move |ctx| {
+        FieldFuture::new(async move {
+            let context = ctx.data::<AuditContext>()?;
+            let body = ctx.args.try_get("input")?.as_value().clone().into_json()?;
+            let req = extract_row(table, body).map_err(graphql_error)?;
+
+            let mut tx = db::begin().await.map_err(graphql_error)?;
+            let row = create_row(&mut tx, table, req, context).await.map_err(graphql_error)?;
+            tx.commit().await.map_err(|err| graphql_error(err.into()))?;
+            context.publish();
+            Ok(Some(FieldValue::owned_any(row)))
+        })
+    })
+    .argument(InputValue::new("input", TypeRef::named_nn(schema_name(table, Variant::Create))))
+}
+
+/// `update_user(id: Int!, input: UserUpdate!, if_match: String): User!`, validated like `PATCH /user/{id}`.
+fn update_field(table: &'static str) -> Field {
+    Field::new(format!("update_{}", table), TypeRef::named_nn(schema_name(table, Variant::Row)), move |ctx| {
+        FieldFuture::new(async move {
+            let context = ctx.data::<AuditContext>()?;
+            let id = id_argument(&ctx)?;
+            let if_match = string_argument(&ctx, "if_match")?;
+            let body = ctx.args.try_get("input")?.as_value().clone().into_json()?;
+            let req = extract_changes(table, body).map_err(graphql_error)?;
+            let values = db::sql_values(table, req).map_err(graphql_error)?;
+
+            let mut tx = db::begin().await.map_err(graphql_error)?;
+            let row = modify_row(&mut tx, table, id, values, &if_match, context).await.map_err(graphql_error)?;
+            tx.commit().await.map_err(|err| graphql_error(err.into()))?;
+            context.publish();
+            Ok(Some(FieldValue::owned_any(row)))
+        })
+    })
+    .argument(InputValue::new("id", TypeRef::named_nn(TypeRef::INT)))
+    .argument(InputValue::new("input", TypeRef::named_nn(schema_name(table, Variant::Update))))
+    .argument(InputValue::new("if_match", TypeRef::named(TypeRef::STRING)))
+}
+
+/// `delete_user(id: Int!, if_match: String): User!`, which soft deletes like `DELETE /user/{id}` if configured.
+fn delete_field(table: &'static str) -> Field {
+    Field::new(format!("delete_{}", table), TypeRef::named_nn(schema_name(table, Variant::Row)), move |ctx| {
+        FieldFuture::new(async move {
+            let context = ctx.data::<AuditContext>()?;
+            let id = id_argument(&ctx)?;
+            let if_match = string_argument(&ctx, "if_match")?;
+
+            let mut tx = db::begin().await.map_err(graphql_error)?;
+            let row = remove_row(&mut tx, table, id, &if_match, context).await.map_err(graphql_error)?;
+            tx.commit().await.map_err(|err| graphql_error(err.into()))?;
+            context.publish();
+            Ok(Some(FieldValue::owned_any(row)))
+        })
+    })
+    .argument(InputValue::new("id", TypeRef::named_nn(TypeRef::INT)))
+    .argument(InputValue::new("if_match", TypeRef::named(TypeRef::STRING)))
+}
+
+/// Reads the `id` argument as a primary key.
+fn id_argument(ctx: &ResolverContext) -> async_graphql::Result<u32> {
+    let id = ctx.args.try_get("id")?.i64()?;
+    u32::try_from(id).map_err(|_| graphql_error(AppError {
+        err_type: ErrorType::BadRequest,
+        message: format!("id should be a non-negative integer that fits in 32 bits, not {}", id),
+        field: Some("id".to_string()),
+    }))
+}
+
+/// Reads an optional boolean argument.
+fn bool_argument(ctx: &ResolverContext, name: &str) -> async_graphql::Result<Option<&'static str>> {
+    match ctx.args.get(name) {
+        Some(value) if !value.is_null() => Ok(Some(if value.boolean()? { "true" } else { "false" })),
+        _ => Ok(None),
+    }
+}
+
+/// Reads an optional string argument.
+fn string_argument(ctx: &ResolverContext, name: &str) -> async_graphql::Result<Option<String>> {
+    match ctx.args.get(name) {
+        Some(value) if !value.is_null() => Ok(Some(value.string()?.to_string())),
+        _ => Ok(None),
+    }
+}
+
+/// Formats a filter value like it would be written in a query string.
+fn query_value(value: Value) -> String {
+    match value {
+        Value::String(text) => text,
+        other => other.to_string(),
+    }
+}
+
+/// Converts an error into a GraphQL error, with its status code and field as extensions.
+fn graphql_error(err: AppError) -> async_graphql::Error {
+    describe_error(err.to_http_status(), err.message, err.field)
+}
+
+/// Builds a GraphQL error with `status` and `field` extensions.
+///
+/// Like `handle_rejection`, server errors are logged and only described to the client in debug mode.
+fn describe_error(status: StatusCode, mut message: String, mut field: Option<String>) -> async_graphql::Error {
+    if status.is_server_error() {
+        let id = uuid::Uuid::new_v4().to_string();
+        eprintln!("error {} ({}): graphql: {}", id, status, message);
+        if !debug_mode() {
+            message = format!("Internal Server Error (error id {})", id);
+            field = None;
+        }
+    }
+    async_graphql::Error::new(message).extend_with(|_, extensions| {
+        extensions.set("status", status.as_u16());
+        if let Some(field) = field {
+            extensions.set("field", field);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_table_gets_queries_and_mutations() {
+        let sdl = gen_schema().expect("the schema should build").sdl();
+        for table in DB_DESIGN.tables.keys() {
+            let row = schema_name(table, Variant::Row);
+            for field in [
+                format!("{}(id: Int!, include_deleted: Boolean): {}", table, row),
+                format!("create_{}(input: {}!): {}!", table, schema_name(table, Variant::Create), row),
+                format!("update_{}(id: Int!, input: {}!, if_match: String): {}!", table, schema_name(table, Variant::Update), row),
+                format!("delete_{}(id: Int!, if_match: String): {}!", table, row),
+            ] {
+                assert!(sdl.contains(&field), "the schema should have {}", field);
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::DB_DESIGN;
+use crate::AppError;
+use crate::db;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+
+/// The outcome of a single readiness check.
+#[derive(serde::Serialize)]
+struct Check {
+    ok: bool,
+    detail: String,
+}
+
+/// The body returned by the readiness route.
+#[derive(serde::Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+// GET <domain>/health
+/// A function that returns a warp route for checking that the process is alive.
+///
+/// This does not touch the database, so it stays cheap enough to be probed often.
+pub(crate) fn health() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("health")
+        .and(warp::get())
+        .and(with_format())
+        .and_then(health_success)
+}
+
+// GET <domain>/ready
+/// A function that returns a warp route for checking that the server can handle requests.
+///
+/// Responds with `503 Service Unavailable` if any of the checks fail.
+pub(crate) fn ready() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("ready")
+        .and(warp::get())
+        .and_then(ready_retrieve)
+        .and(with_format())
+        .and_then(ready_success)
+}
+
+/// Replies with a success code as long as the server is running.
+async fn health_success(format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(serde_json::json!({ "status": "ok" })),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+/// Runs each of the readiness checks.
+async fn ready_retrieve() -> Result<Readiness, Rejection> {
+    let mut checks = BTreeMap::new();
+
+    // The schema is parsed from the SQL dump when the global is first used
+    let table_count = DB_DESIGN.tables.len();
+    checks.insert("schema", Check {
+        ok: table_count > 0,
+        detail: format!("{} table(s) loaded", table_count),
+    });
+
+    checks.insert("database", match db::ping().await {
+        Ok(()) => Check { ok: true, detail: "reachable".to_string() },
+        Err(err) => failed_check("unreachable", err),
+    });
+
+    checks.insert("migrations", match db::pending_migrations().await {
+        Ok(pending) if pending.is_empty() => Check { ok: true, detail: "up to date".to_string() },
+        Ok(pending) => Check { ok: false, detail: format!("{} migration(s) pending: {:?}", pending.len(), pending) },
+        Err(err) => failed_check("migration check failed", err),
+    });
+
+    Ok(Readiness {
+        ready: checks.values().all(|check| check.ok),
+        checks,
+    })
+}
+
+/// Describes a check that failed with an error.
+///
+/// The report is public, so like `handle_rejection`, the error is only logged, under an id that the detail includes.
+fn failed_check(detail: &str, err: AppError) -> Check {
+    let id = uuid::Uuid::new_v4().to_string();
+    eprintln!("error {} (readiness): {}", id, err.message);
+    Check { ok: false, detail: format!("{} (error id {})", detail, id) }
+}
+
+/// Replies with the readiness report, using `503` if the server is not ready.
+async fn ready_success(readiness: Readiness, format: Format) -> Result<impl Reply, Rejection> {
+    let status = if readiness.ready {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    respond(Ok(readiness), status, format)
+}
@@ -0,0 +1,210 @@
+use sha2::Digest;
+use sha2::Sha256;
+use sqlx::MySqlConnection;
+use sqlx::Row as SqlxRow;
+use warp::Rejection;
+use warp::Filter;
+
+use crate::env;
+use crate::AppError;
+use crate::ErrorType;
+use crate::auth::Actor;
+use crate::auth::actor_name;
+use crate::auth::with_actor;
+use crate::audit::AuditContext;
+
+/// The table stored responses are kept in, created by a migration.
+const IDEMPOTENCY_TABLE: &str = "idempotency_keys";
+
+/// The header clients use to make a request safe to retry.
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// The longest key a client may send.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Extracts the `Idempotency-Key` header, if any, see `check_key`.
+pub(crate) fn with_idempotency_key() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER)
+        .and(with_actor())
+        .and_then(|key: Option<String>, actor: Option<Actor>| async move {
+            check_key(key, &actor).map_err(AppError::into_warp)
+        })
+}
+
+/// Checks the length of a key, and that it was sent with a bearer token.
+///
+/// Stored responses belong to a token, so anonymous clients would otherwise share, and replay, each other's keys.
+fn check_key(key: Option<String>, actor: &Option<Actor>) -> Result<Option<String>, AppError> {
+    match key {
+        Some(key) if key.is_empty() || key.len() > MAX_KEY_LENGTH => Err(AppError::new(
+            ErrorType::BadRequest,
+            format!("Idempotency-Key should be between 1 and {} characters long", MAX_KEY_LENGTH)
+        )),
+        Some(_) if actor.is_none() => Err(AppError::new(
+            ErrorType::Unauthorized,
+            "Unauthorized: Idempotency-Key can only be sent with a bearer token".to_string()
+        )),
+        key => Ok(key),
+    }
+}
+
+/// Hashes the route and validated contents of a request, to tell whether a key is reused for a different request.
+///
+/// `serde_json` keeps map keys sorted, so the same request always produces the same hash.
+pub(crate) fn fingerprint<T: serde::Serialize>(scope: &str, request: &T) -> Result<String, AppError> {
+    let mut hasher = Sha256::new();
+    hasher.update(scope.as_bytes());
+    hasher.update(serde_json::to_vec(request)?);
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Returns how long stored responses are replayed for, in hours.
+fn window_hours() -> u32 {
+    env().map_or(24, |env| env.idempotency_window_hours)
+}
+
+/// A response stored with a key.
+struct Stored {
+    fingerprint: String,
+    response: String,
+    /// How long ago the response was stored, in seconds.
+    age_seconds: i64,
+}
+
+/// Looks up the response stored for a client's key within the replay window, see `replay`.
+pub(crate) async fn find<T: serde::de::DeserializeOwned>(
+    conn: &mut MySqlConnection,
+    context: &AuditContext,
+    key: &str,
+    fingerprint: &str,
+) -> Result<Option<T>, AppError> {
+    let sql = format!(
+        "SELECT `fingerprint`, `response`, TIMESTAMPDIFF(SECOND, `created_at`, UTC_TIMESTAMP()) AS `age` FROM `{}` WHERE `actor` = ? AND `idempotency_key` = ?",
+        IDEMPOTENCY_TABLE
+    );
+    let row = sqlx::query(&sql)
+        .bind(actor_name(&context.actor))
+        .bind(key)
+        .fetch_optional(conn)
+        .await?;
+    let stored = match row {
+        Some(row) => Some(Stored {
+            fingerprint: row.try_get("fingerprint")?,
+            response: row.try_get("response")?,
+            age_seconds: row.try_get("age")?,
+        }),
+        None => None,
+    };
+    replay(stored, fingerprint, window_hours())
+}
+
+/// Returns the stored response if it is younger than the window, and was stored for the same request.
+///
+/// Errors with `422 Unprocessable Entity` if the key was used for a different request.
+/// Expired responses are ignored, so that the key can be used again.
+fn replay<T: serde::de::DeserializeOwned>(stored: Option<Stored>, fingerprint: &str, window_hours: u32) -> Result<Option<T>, AppError> {
+    match stored {
+        Some(stored) if stored.age_seconds >= i64::from(window_hours) * 3600 => Ok(None),
+        None => Ok(None),
+        Some(stored) if stored.fingerprint == fingerprint => Ok(Some(serde_json::from_str(&stored.response)?)),
+        Some(_) => Err(AppError {
+            err_type: ErrorType::UnprocessableEntity,
+            message: "Idempotency-Key was already used for a different request".to_string(),
+            field: None,
+        }),
+    }
+}
+
+/// Stores the response to a request, so that retries with the same key replay it.
+///
+/// This should use the same transaction as the change, so that the response is only kept if the change is.
+/// If another request with the same key is stored first, this errors with `409 Conflict`.
+pub(crate) async fn save<T: serde::Serialize>(
+    conn: &mut MySqlConnection,
+    context: &AuditContext,
+    key: &str,
+    fingerprint: &str,
+    response: &T,
+) -> Result<(), AppError> {
+    // A key can be reused once its stored response has expired
+    let sql = format!(
+        "DELETE FROM `{}` WHERE `actor` = ? AND `idempotency_key` = ? AND `created_at` <= UTC_TIMESTAMP() - INTERVAL ? HOUR",
+        IDEMPOTENCY_TABLE
+    );
+    sqlx::query(&sql)
+        .bind(actor_name(&context.actor))
+        .bind(key)
+        .bind(window_hours())
+        .execute(&mut *conn)
+        .await?;
+
+    // The column's default is the session time zone's CURRENT_TIMESTAMP, but the window is checked in UTC
+    let sql = format!(
+        "INSERT INTO `{}` (`actor`, `idempotency_key`, `fingerprint`, `response`, `created_at`) VALUES (?, ?, ?, ?, UTC_TIMESTAMP())",
+        IDEMPOTENCY_TABLE
+    );
+    sqlx::query(&sql)
+        .bind(actor_name(&context.actor))
+        .bind(key)
+        .bind(fingerprint)
+        .bind(serde_json::to_string(response)?)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Deletes every stored response older than the replay window, returning how many were deleted.
+pub(crate) async fn purge(conn: &mut MySqlConnection) -> Result<u64, AppError> {
+    let sql = format!(
+        "DELETE FROM `{}` WHERE `created_at` <= UTC_TIMESTAMP() - INTERVAL ? HOUR",
+        IDEMPOTENCY_TABLE
+    );
+    Ok(sqlx::query(&sql).bind(window_hours()).execute(conn).await?.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+
+    fn stored(fingerprint: &str, age_seconds: i64) -> Option<Stored> {
+        Some(Stored {
+            fingerprint: fingerprint.to_string(),
+            response: r#"{"id":7}"#.to_string(),
+            age_seconds,
+        })
+    }
+
+    #[test]
+    fn retries_replay_the_stored_response() {
+        let response: Option<serde_json::Value> = replay(stored("abc", 60), "abc", 24).unwrap();
+        assert_eq!(response, Some(serde_json::json!({ "id": 7 })));
+        assert!(replay::<serde_json::Value>(None, "abc", 24).unwrap().is_none());
+    }
+
+    #[test]
+    fn keys_reused_for_other_requests_are_rejected() {
+        let err = replay::<serde_json::Value>(stored("abc", 60), "def", 24).unwrap_err();
+        assert_eq!(err.to_http_status(), warp::http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn expired_responses_are_not_replayed() {
+        assert!(replay::<serde_json::Value>(stored("abc", 3599), "abc", 1).unwrap().is_some());
+        assert!(replay::<serde_json::Value>(stored("abc", 3600), "abc", 1).unwrap().is_none());
+        // An expired key can be reused for a different request
+        assert!(replay::<serde_json::Value>(stored("abc", 7200), "def", 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn keys_need_a_token() {
+        let actor = Some(Actor { name: "ann".to_string(), role: Role::Basic });
+        assert_eq!(check_key(Some("k1".to_string()), &actor).unwrap().as_deref(), Some("k1"));
+        assert_eq!(check_key(None, &None).unwrap(), None);
+
+        let err = check_key(Some("k1".to_string()), &None).unwrap_err();
+        assert_eq!(err.to_http_status(), warp::http::StatusCode::UNAUTHORIZED);
+        let err = check_key(Some("k".repeat(MAX_KEY_LENGTH + 1)), &actor).unwrap_err();
+        assert_eq!(err.to_http_status(), warp::http::StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use serde_json::Map;
+use serde_json::Value;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use warp::hyper::body::Bytes;
+use rustract::field::FieldDesign;
+use rustract::types::DataType;
+use rustract::types::DataTypeValue;
+
+use crate::DB_DESIGN;
+use crate::ErrorType;
+use crate::AppError;
+use crate::Check;
+use crate::db;
+use crate::post::create_user;
+use crate::post::post_extract;
+use crate::bulk::BULK_MAX_BODY_SIZE;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::format::essence;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
+
+/// A CSV row that could not be imported.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct RejectedRow {
+    /// The line the row starts on, counting the header as line 1.
+    pub line: u64,
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+/// The reply to an import.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct ImportReport {
+    /// The number of rows inserted.
+    pub imported: usize,
+    pub rejected: Vec<RejectedRow>,
+}
+
+/// The rows of an import that passed validation, with their line numbers, and the ones that did not.
+type ImportRows = (Vec<(u64, HashMap<String, DataTypeValue>)>, Vec<RejectedRow>);
+
+// POST <domain>/user/import
+/// A function that returns a warp route for adding users from a CSV file.
+///
+/// The header row names the field of each column, and empty cells are treated as missing.
+/// A `deleted_at` column, as in exports of soft-deleting tables, is ignored, since imported users are new.
+/// Every valid row is inserted, and the reply lists the rows that were rejected.
+pub(crate) fn import_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / "import")
+        .and(warp::post())
+        .and(check_accept())
+        .and(warp::body::content_length_limit(BULK_MAX_BODY_SIZE))
+        .and(warp::header::optional::<String>(warp::http::header::CONTENT_TYPE.as_str()))
+        .and(warp::body::bytes())
+        .and_then(import_extract)
+        .and(with_audit())
+        .and_then(import_insert)
+        .and(with_format())
+        .and_then(import_success)
+}
+
+/// Parses the CSV body and validates each row like a POST body.
+async fn import_extract(content_type: Option<String>, body: Bytes) -> Result<ImportRows, warp::reject::Rejection> {
+    if content_type.is_some_and(|content_type| essence(&content_type) != "text/csv") {
+        Err(AppError::new(
+            ErrorType::UnsupportedMediaType,
+            "Unsupported Media Type: imports should be sent as text/csv".to_string()
+        ))?
+    }
+
+    let design = DB_DESIGN.tables.get("user").check()?;
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(&body[..]);
+    let headers = reader.headers().map_err(|err| bad_csv(&err))?.clone();
+    for header in headers.iter() {
+        if !design.fields.contains_key(header) && header != db::SOFT_DELETE_COLUMN {
+            Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("unknown column {}: the header row should only name fields of user", header),
+                field: Some(header.to_string()),
+            })?
+        }
+    }
+
+    let mut valid = Vec::new();
+    let mut rejected = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                rejected.push(RejectedRow {
+                    line: err.position().map_or(0, |position| position.line()),
+                    error: bad_csv(&err).message,
+                    field: None,
+                });
+                continue;
+            },
+        };
+        let line = record.position().map_or(0, |position| position.line());
+
+        match record_to_json(&headers, &record).map(Value::Object) {
+            Ok(body) => match post_extract(body).await {
+                Ok(req) => valid.push((line, req)),
+                Err(rejection) => rejected.push(rejected_row(line, &rejection)),
+            },
+            Err(err) => rejected.push(RejectedRow { line, error: err.message, field: err.field }),
+        }
+    }
+
+    Ok((valid, rejected))
+}
+
+/// Converts a CSV record into the JSON object `post_extract` expects, using each field's type.
+fn record_to_json(headers: &csv::StringRecord, record: &csv::StringRecord) -> Result<Map<String, Value>, AppError> {
+    let design = DB_DESIGN.tables.get("user").check()?;
+    let mut map = Map::new();
+    for (header, cell) in headers.iter().zip(record.iter()) {
+        if cell.is_empty() || header == db::SOFT_DELETE_COLUMN {
+            continue;
+        }
+        let field = design.fields.get(header).check()?;
+        let value = cell_to_json(field, cell).ok_or_else(|| AppError {
+            err_type: ErrorType::BadRequest,
+            message: format!("field {} is not formatted properly: \"{}\"", header, cell),
+            field: Some(header.to_string()),
+        })?;
+        map.insert(header.to_string(), value);
+    }
+    Ok(map)
+}
+
+/// Parses a CSV cell as the JSON type of a field, since every cell is text.
+fn cell_to_json(field: &FieldDesign, cell: &str) -> Option<Value> {
+    match field.datatype {
+        DataType::Signed => cell.parse::<i64>().ok().map(Value::from),
+        DataType::Unsigned => cell.parse::<u64>().ok().map(Value::from),
+        DataType::Float => cell.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number),
+        DataType::Boolean => match cell.to_lowercase().as_str() {
+            "true" | "1" => Some(Value::Bool(true)),
+            "false" | "0" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        DataType::Json => serde_json::from_str(cell).ok(),
+        _ => Some(Value::String(cell.to_string())),
+    }
+}
+
+/// Describes a CSV syntax error.
+fn bad_csv(err: &csv::Error) -> AppError {
+    AppError::new(ErrorType::BadRequest, format!("failed to parse CSV: {}", err))
+}
+
+/// Describes a row that failed validation.
+fn rejected_row(line: u64, rejection: &Rejection) -> RejectedRow {
+    match rejection.find::<AppError>() {
+        Some(err) => RejectedRow { line, error: err.message.clone(), field: err.field.clone() },
+        None => RejectedRow { line, error: format!("{:?}", rejection), field: None },
+    }
+}
+
+/// Inserts the valid rows in one transaction.
+///
+/// Rows that the database rejects, such as duplicates of a unique field, are added to the report.
+async fn import_insert(req: ImportRows, context: AuditContext) -> Result<ImportReport, warp::reject::Rejection> {
+    let (valid, mut rejected) = req;
+    let mut imported = 0;
+
+    let mut tx = db::begin().await?;
+    for (line, row) in valid {
+        match create_user(&mut tx, row, &context).await {
+            Ok(_) => imported += 1,
+            Err(err) if err.to_http_status().is_client_error() => {
+                rejected.push(RejectedRow { line, error: err.message, field: err.field });
+            },
+            Err(err) => Err(err)?,
+        }
+    }
+    tx.commit().await.map_err(AppError::from)?;
+    context.publish();
+
+    rejected.sort_by_key(|row| row.line);
+    Ok(ImportReport { imported, rejected })
+}
+
+/// Replies with the number of imported rows and the rejected rows.
+async fn import_success(report: ImportReport, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(report),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::csv_cell;
+    use crate::export::csv_line;
+    use crate::export::export_columns;
+
+    /// Writes rows as `export_user` would for a soft-deleting table.
+    fn export(rows: &[Value]) -> Bytes {
+        let mut columns = export_columns("user").unwrap();
+        columns.push(db::SOFT_DELETE_COLUMN.to_string());
+        let mut body = csv_line(columns.iter().map(String::as_str)).unwrap();
+        for row in rows {
+            let cells: Vec<String> = columns.iter().map(|column| csv_cell(row.get(column).unwrap_or(&Value::Null))).collect();
+            body.extend(csv_line(cells.iter().map(String::as_str)).unwrap());
+        }
+        Bytes::from(body)
+    }
+
+    fn error(rejection: Rejection) -> (String, Option<String>) {
+        let err = rejection.find::<AppError>().unwrap();
+        (err.message.clone(), err.field.clone())
+    }
+
+    #[tokio::test]
+    async fn exports_can_be_imported() {
+        let body = export(&[
+            serde_json::json!({ "id": 1, "name": "Ann, Jr.", "email": "ann@example.com", "type": "Admin", "deleted_at": "2024-01-01 00:00:00" }),
+            serde_json::json!({ "id": 2, "email": "bob@example.com", "type": "Basic" }),
+            serde_json::json!({ "id": 3, "name": "Cy", "email": "cy@example.com", "registered": "2024-02-02", "type": "Mod" }),
+        ]);
+        let (valid, rejected) = import_extract(Some("Text/CSV; charset=utf-8".to_string()), body).await.unwrap();
+
+        let lines: Vec<u64> = valid.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 4]);
+        assert_eq!(valid[0].1["name"].to_string(), "Ann, Jr.");
+        assert!(!valid[0].1.contains_key(db::SOFT_DELETE_COLUMN));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].line, 3);
+        assert_eq!(rejected[0].field.as_deref(), Some("name"));
+    }
+
+    #[tokio::test]
+    async fn unknown_columns_and_media_types_are_rejected() {
+        let body = Bytes::from("name,email,type,nickname\nAnn,ann@example.com,Admin,A\n");
+        let (message, field) = error(import_extract(None, body).await.unwrap_err());
+        assert_eq!(message, "unknown column nickname: the header row should only name fields of user");
+        assert_eq!(field.as_deref(), Some("nickname"));
+
+        let rejection = import_extract(Some("application/json".to_string()), Bytes::from("[]")).await.unwrap_err();
+        assert_eq!(rejection.find::<AppError>().unwrap().to_http_status(), warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn malformed_lines_are_reported_by_number() {
+        let body = Bytes::from("name,email,type\nAnn,ann@example.com,Admin\nBob,bob@example.com\nCy,cy@example.com,Mod\n");
+        let (valid, rejected) = import_extract(Some("text/csv".to_string()), body).await.unwrap();
+        assert_eq!(valid.len(), 2);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].line, 3);
+        assert!(rejected[0].error.starts_with("failed to parse CSV"));
+    }
+}
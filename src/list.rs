@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::DB_DESIGN;
+use crate::ErrorType;
+use crate::AppError;
+use crate::Check;
+use crate::db;
+use crate::db::Row;
+use crate::db::Condition;
+use crate::db::SqlValue;
+use crate::routes::respond;
+use crate::routes::respond_stream;
+use crate::format::ListFormat;
+use crate::format::NDJSON_MIME;
+use crate::format::with_list_format;
+use crate::auth::Actor;
+use crate::auth::with_actor;
+use crate::auth::include_deleted;
+use crate::filter::parse_filter;
+use crate::cache;
+use crate::cache::CacheKey;
+use crate::cache::with_cache_key;
+
+/// The number of rows returned when no `limit` is given.
+pub(crate) const DEFAULT_LIMIT: u32 = 100;
+
+/// The largest `limit` a client may ask for.
+pub(crate) const MAX_LIMIT: u32 = 1000;
+
+/// The parsed query of a list request.
+pub(crate) struct ListQuery {
+    /// The conditions a row must match: `column = value` pairs, and the `filter` expression if given.
+    pub filters: Vec<Condition>,
+    pub include_deleted: bool,
+    /// The columns to read, from `?fields=name,type`, or every column if not given.
+    pub fields: Option<Vec<String>>,
+    /// The `limit` parameter, if it was given.
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+// GET <domain>/user
+/// A function that returns a warp route for listing users.
+///
+/// Any field can be used as a filter, e.g. `?type=Admin`, and pages are chosen with `limit` and `offset`.
+/// More complex conditions can be written as `?filter=type in ('Admin','Mod') and registered >= '2023-01-01'`.
+/// `?fields=name,type` only reads and returns those fields.
+/// Soft-deleted users are only listed for admins passing `?include_deleted=true`.
+/// Clients that accept `application/x-ndjson` get the users streamed one per line, with no default `limit`.
+/// Other lists are cached if the response cache is enabled.
+pub(crate) fn list_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(list_extract)
+        .and(with_list_format())
+        .and(with_cache_key())
+        .and_then(list_success)
+}
+
+/// Checks the query parameters against the table's fields.
+async fn list_extract(query: HashMap<String, String>, actor: Option<Actor>) -> Result<ListQuery, warp::reject::Rejection> {
+    Ok(parse_list_query("user", query, &actor)?)
+}
+
+/// Splits a list query into filters and paging options.
+///
+/// Unknown parameters are rejected, so that a typo does not silently return every row.
+pub(crate) fn parse_list_query(table: &str, query: HashMap<String, String>, actor: &Option<Actor>) -> Result<ListQuery, AppError> {
+    let design = DB_DESIGN.tables.get(table).check()?;
+    let mut list_query = ListQuery {
+        filters: Vec::new(),
+        include_deleted: include_deleted(query.get("include_deleted").map(String::as_str), actor)?,
+        fields: parse_fields(table, query.get("fields").map(String::as_str))?,
+        limit: None,
+        offset: 0,
+    };
+
+    for (key, value) in query {
+        match key.as_str() {
+            "include_deleted" | "fields" => {},
+            "filter" => list_query.filters.push(parse_filter(table, &value)?),
+            "limit" => list_query.limit = Some(parse_number(&key, &value)?),
+            "offset" => list_query.offset = parse_number(&key, &value)?,
+            _ if design.fields.contains_key(&key) => list_query.filters.push(Condition::equals(&key, SqlValue::String(value))),
+            _ => return Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("unknown query parameter {}: expected a field of {}, filter, fields, limit, offset or include_deleted", key, table),
+                field: Some(key),
+            }),
+        }
+    }
+
+    // Sorted so that the generated SQL is the same for the same filters
+    list_query.filters.sort_by(|a, b| a.sql.cmp(&b.sql));
+    Ok(list_query)
+}
+
+/// Parses a `fields` parameter, a comma-separated list of the columns to return.
+///
+/// Each name must be a field of the table, or `deleted_at` if the table soft deletes.
+/// Duplicates are ignored, and the columns keep the order they were listed in.
+pub(crate) fn parse_fields(table: &str, value: Option<&str>) -> Result<Option<Vec<String>>, AppError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let design = DB_DESIGN.tables.get(table).check()?;
+
+    let mut fields: Vec<String> = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        let known = design.fields.contains_key(name)
+            || (name == db::SOFT_DELETE_COLUMN && db::soft_deletes(table));
+        if !known {
+            return Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("fields should be a comma-separated list of fields of {}, but \"{}\" is not one", table, name),
+                field: Some("fields".to_string()),
+            });
+        }
+        if !fields.iter().any(|field| field == name) {
+            fields.push(name.to_string());
+        }
+    }
+    Ok(Some(fields))
+}
+
+/// Extracts the `?fields=` parameter of a single-row route, see `parse_fields`.
+///
+/// Other query parameters are ignored.
+pub(crate) fn with_fields(table: &'static str) -> impl Filter<Extract = (Option<Vec<String>>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and_then(move |query: HashMap<String, String>| async move {
+            parse_fields(table, query.get("fields").map(String::as_str)).map_err(AppError::into_warp)
+        })
+}
+
+/// Parses a paging parameter.
+pub(crate) fn parse_number(key: &str, value: &str) -> Result<u32, AppError> {
+    value.parse().map_err(|_| AppError {
+        err_type: ErrorType::BadRequest,
+        message: format!("query parameter {} should be a non-negative integer, not \"{}\"", key, value),
+        field: Some(key.to_string()),
+    })
+}
+
+/// Uses the filters to make an SQL SELECT query, unless the list is cached.
+async fn list_retrieve(query: ListQuery, key: CacheKey) -> Result<Vec<Row>, AppError> {
+    if let Some(serde_json::Value::Array(users)) = cache::lookup(&key) {
+        return Ok(users.into_iter().filter_map(|user| match user {
+            serde_json::Value::Object(user) => Some(user),
+            _ => None,
+        }).collect());
+    }
+    let generation = cache::generation();
+
+    let mut conn = db::acquire().await?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let users = db::fetch_rows(&mut conn, "user", query.fields.as_deref(), query.filters, query.include_deleted, limit, query.offset).await?;
+    cache::store(key, generation, serde_json::Value::Array(users.iter().cloned().map(serde_json::Value::Object).collect()));
+    Ok(users)
+}
+
+/// Replies with the matching users, either as one array or streamed as NDJSON.
+///
+/// Streamed rows are read from a database cursor, so any number of them can be sent in constant memory.
+async fn list_success(query: ListQuery, format: ListFormat, key: CacheKey) -> Result<warp::reply::Response, Rejection> {
+    match format {
+        ListFormat::Ndjson => {
+            let rows = db::stream_rows("user", query.fields.as_deref(), query.filters, query.include_deleted, query.limit, query.offset)?;
+            respond_stream(rows, NDJSON_MIME, Vec::new(), Box::new(ndjson_line)).await
+        },
+        ListFormat::Body(format) => Ok(respond(
+            Ok(list_retrieve(query, key).await?),
+            warp::http::StatusCode::OK,
+            format
+        )?.into_response()),
+    }
+}
+
+/// Encodes a row as one line of NDJSON.
+fn ndjson_line(row: &Row) -> Result<Vec<u8>, AppError> {
+    let mut line = serde_json::to_vec(row)?;
+    line.push(b'\n');
+    Ok(line)
+}
@@ -0,0 +1,568 @@
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::DB_DESIGN;
+use crate::env;
+use crate::routes::respond;
+use crate::routes::RouteBody;
+use crate::routes::ROUTE_DOCS;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::format::NDJSON_MIME;
+use crate::schema::Variant;
+use crate::schema::schema_name;
+use crate::schema::table_schema;
+use crate::bulk::BULK_MAX_OPERATIONS;
+use crate::list::DEFAULT_LIMIT;
+use crate::list::MAX_LIMIT;
+use crate::changes::change_schema;
+
+/// The media types that request and response bodies can use.
+const MEDIA_TYPES: &[&str] = &["application/json", "application/msgpack", "application/cbor"];
+
+/// A Swagger UI page that loads the generated document.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8" />
+    <title>rustful_api</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+</body>
+</html>
+"##;
+
+// GET <domain>/openapi.json
+/// A function that returns a warp route for the OpenAPI document describing this server.
+pub(crate) fn openapi() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("openapi.json")
+        .and(warp::get())
+        .and(with_format())
+        .and_then(openapi_success)
+}
+
+// GET <domain>/docs
+/// A function that returns a warp route for a Swagger UI page, if `swagger_ui` is enabled in the config.
+pub(crate) fn docs() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("docs")
+        .and(warp::get())
+        .and_then(docs_success)
+}
+
+/// Replies with the generated OpenAPI document.
+async fn openapi_success(format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(gen_openapi()),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+/// Replies with the Swagger UI page, or `404 Not Found` if it is disabled.
+async fn docs_success() -> Result<impl Reply, Rejection> {
+    if env().is_some_and(|env| env.swagger_ui) {
+        Ok(warp::reply::html(SWAGGER_UI))
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+/// Generates an OpenAPI 3.1 document from the database design and `ROUTE_DOCS`.
+///
+/// OpenAPI 3.1 uses JSON Schema draft 2020-12, so the table schemas are used as-is.
+pub(crate) fn gen_openapi() -> Value {
+    let mut schemas = Map::new();
+
+    // Each table gets a schema for its rows, POST bodies and PATCH bodies
+    let mut tables: Vec<(&String, _)> = DB_DESIGN.tables.iter().collect();
+    tables.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, table) in tables {
+        for variant in [Variant::Row, Variant::Create, Variant::Update] {
+            schemas.insert(schema_name(name, variant), table_schema(name, table, variant));
+        }
+        schemas.insert(format!("{}BulkOperations", schema_name(name, Variant::Row)), json!({
+            "type": "array",
+            "maxItems": BULK_MAX_OPERATIONS,
+            "items": {
+                "oneOf": [
+                    {
+                        "type": "object",
+                        "properties": {
+                            "op": { "const": "create" },
+                            "body": { "$ref": format!("#/components/schemas/{}", schema_name(name, Variant::Create)) },
+                        },
+                        "required": ["op", "body"],
+                    },
+                    {
+                        "type": "object",
+                        "properties": {
+                            "op": { "const": "update" },
+                            "id": { "type": "integer", "minimum": 0 },
+                            "body": { "$ref": format!("#/components/schemas/{}", schema_name(name, Variant::Update)) },
+                            "if_match": { "type": "string" },
+                        },
+                        "required": ["op", "id", "body"],
+                    },
+                    {
+                        "type": "object",
+                        "properties": {
+                            "op": { "const": "delete" },
+                            "id": { "type": "integer", "minimum": 0 },
+                            "if_match": { "type": "string" },
+                        },
+                        "required": ["op", "id"],
+                    },
+                ],
+            },
+        }));
+        schemas.insert(format!("{}List", schema_name(name, Variant::Row)), json!({
+            "type": "array",
+            "items": { "$ref": format!("#/components/schemas/{}", schema_name(name, Variant::Row)) },
+        }));
+        schemas.insert(format!("{}SearchResults", schema_name(name, Variant::Row)), json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "score": { "type": "number" },
+                    "row": { "$ref": format!("#/components/schemas/{}", schema_name(name, Variant::Row)) },
+                    "highlights": {
+                        "type": "object",
+                        "description": "The matching columns, with the matched words wrapped in <mark> and the rest HTML-escaped.",
+                        "additionalProperties": { "type": "string" },
+                    },
+                },
+                "required": ["score", "row", "highlights"],
+            },
+        }));
+    }
+
+    schemas.insert("ErrorMessage".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "code": { "type": "integer" },
+            "message": { "type": "string" },
+            "error_id": { "type": "string", "description": "Identifies the server log entry for 5xx errors." },
+        },
+        "required": ["code", "message"],
+    }));
+    schemas.insert("Problem".to_string(), json!({
+        "type": "object",
+        "description": "An RFC 7807 problem details object.",
+        "properties": {
+            "type": { "type": "string" },
+            "title": { "type": "string" },
+            "status": { "type": "integer" },
+            "detail": { "type": "string" },
+            "instance": { "type": "string" },
+            "error_id": { "type": "string" },
+            "errors": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": { "field": { "type": "string" } },
+                },
+            },
+        },
+        "required": ["type", "title", "status", "detail", "instance"],
+    }));
+    schemas.insert("Document".to_string(), json!({
+        "type": "object",
+        "description": "A generated JSON document, such as an OpenAPI document or JSON Schema.",
+    }));
+    schemas.insert("CacheStats".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "enabled": { "type": "boolean" },
+            "capacity": { "type": "integer", "minimum": 0 },
+            "ttl_seconds": { "type": "integer", "minimum": 0 },
+            "entries": { "type": "integer", "minimum": 0 },
+            "hits": { "type": "integer", "minimum": 0 },
+            "misses": { "type": "integer", "minimum": 0 },
+            "hit_ratio": { "type": "number", "minimum": 0, "maximum": 1 },
+            "evictions": { "type": "integer", "minimum": 0 },
+            "invalidations": { "type": "integer", "minimum": 0 },
+        },
+        "required": ["enabled", "capacity", "ttl_seconds", "entries", "hits", "misses", "hit_ratio", "evictions", "invalidations"],
+    }));
+    schemas.insert("RelatedRows".to_string(), json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "description": "A row of the table named in the path.",
+        },
+    }));
+    schemas.insert("Count".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "count": { "type": "integer", "minimum": 0 },
+        },
+        "required": ["count"],
+    }));
+    schemas.insert("Aggregates".to_string(), json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": {
+                "group": {
+                    "type": "object",
+                    "description": "The values of the group_by fields shared by the group's rows.",
+                },
+                "metrics": {
+                    "type": "object",
+                    "description": "The metrics of the group, keyed as they were asked for, e.g. count or avg:id.",
+                },
+            },
+            "required": ["group", "metrics"],
+        },
+    }));
+    schemas.insert("BulkResponse".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "mode": { "type": "string", "enum": ["atomic", "best_effort"] },
+            "committed": { "type": "boolean" },
+            "results": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "index": { "type": "integer" },
+                        "status": { "type": "integer" },
+                        "row": { "type": "object" },
+                        "error": { "type": "string" },
+                        "field": { "type": "string" },
+                    },
+                    "required": ["index", "status"],
+                },
+            },
+        },
+    }));
+    schemas.insert("ImportReport".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "imported": { "type": "integer" },
+            "rejected": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "line": { "type": "integer" },
+                        "error": { "type": "string" },
+                        "field": { "type": "string" },
+                    },
+                    "required": ["line", "error"],
+                },
+            },
+        },
+    }));
+    schemas.insert("Change".to_string(), change_schema());
+    schemas.insert("AuditLog".to_string(), json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": {
+                "id": { "type": "integer" },
+                "table_name": { "type": "string" },
+                "row_id": { "type": "integer" },
+                "action": { "type": "string", "enum": ["create", "update", "delete", "restore"] },
+                "actor": { "type": "string" },
+                "request_id": { "type": "string" },
+                "before": { "type": ["object", "null"] },
+                "after": { "type": ["object", "null"] },
+                "created_at": { "type": "string" },
+            },
+        },
+    }));
+    schemas.insert("WebhookRequest".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "url": { "type": "string", "format": "uri" },
+            "table": { "type": "string", "description": "Every table if missing." },
+            "actions": {
+                "type": "array",
+                "items": { "type": "string", "enum": ["create", "update", "delete", "restore"] },
+                "description": "Every action if empty.",
+            },
+            "secret": { "type": "string", "minLength": 16, "description": "Generated if missing." },
+        },
+        "required": ["url"],
+        "additionalProperties": false,
+    }));
+    schemas.insert("Webhook".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "id": { "type": "integer" },
+            "url": { "type": "string" },
+            "table": { "type": ["string", "null"] },
+            "actions": { "type": "array", "items": { "type": "string" } },
+            "secret": { "type": "string", "description": "Only included when the webhook is registered." },
+            "created_by": { "type": "string" },
+            "created_at": { "type": "string" },
+        },
+    }));
+    schemas.insert("Webhooks".to_string(), json!({ "type": "array", "items": schema_ref("Webhook") }));
+    schemas.insert("WebhookDelivery".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "id": { "type": "integer" },
+            "webhook_id": { "type": "integer" },
+            "action": { "type": "string" },
+            "payload": { "type": "object" },
+            "state": { "type": "string", "enum": ["pending", "delivered", "dead"] },
+            "attempts": { "type": "integer" },
+            "response_status": { "type": ["integer", "null"] },
+            "last_error": { "type": ["string", "null"] },
+            "next_attempt_at": { "type": "string" },
+            "created_at": { "type": "string" },
+            "delivered_at": { "type": ["string", "null"] },
+        },
+    }));
+    schemas.insert("WebhookDeliveries".to_string(), json!({ "type": "array", "items": schema_ref("WebhookDelivery") }));
+    schemas.insert("GraphQLRequest".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "query": { "type": "string" },
+            "variables": { "type": "object" },
+            "operationName": { "type": "string" },
+        },
+        "required": ["query"],
+    }));
+    schemas.insert("GraphQLResponse".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "data": { "type": ["object", "null"] },
+            "errors": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "message": { "type": "string" },
+                        "path": { "type": "array" },
+                        "extensions": {
+                            "type": "object",
+                            "properties": {
+                                "status": { "type": "integer" },
+                                "field": { "type": "string" },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    }));
+    schemas.insert("Health".to_string(), json!({
+        "type": "object",
+        "properties": { "status": { "type": "string" } },
+    }));
+    schemas.insert("Readiness".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "ready": { "type": "boolean" },
+            "checks": {
+                "type": "object",
+                "additionalProperties": {
+                    "type": "object",
+                    "properties": {
+                        "ok": { "type": "boolean" },
+                        "detail": { "type": "string" },
+                    },
+                },
+            },
+        },
+    }));
+
+    let mut paths = Map::new();
+    for route in ROUTE_DOCS {
+        let mut operation = Map::new();
+        operation.insert("summary".to_string(), json!(route.summary));
+        if let Some(table) = route.table {
+            operation.insert("tags".to_string(), json!([table]));
+        }
+
+        // Ids are parsed by warp as `u32`, and any other parameters are strings
+        let mut parameters: Vec<Value> = route.path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": if name == "id" {
+                    json!({ "type": "integer", "minimum": 0 })
+                } else {
+                    json!({ "type": "string" })
+                },
+            }))
+            .collect();
+        parameters.extend(route.query.iter().map(|name| query_parameter(name)));
+        if !parameters.is_empty() {
+            operation.insert("parameters".to_string(), json!(parameters));
+        }
+
+        let body_content = match (route.body, route.table) {
+            (Some(RouteBody::Create), Some(table)) => Some(content(&schema_ref(&schema_name(table, Variant::Create)))),
+            (Some(RouteBody::Update), Some(table)) => Some(content(&schema_ref(&schema_name(table, Variant::Update)))),
+            (Some(RouteBody::Bulk), Some(table)) => Some(content(&schema_ref(&format!("{}BulkOperations", schema_name(table, Variant::Row))))),
+            (Some(RouteBody::Csv), _) => Some(csv_content()),
+            (Some(RouteBody::Webhook), _) => Some(content(&schema_ref("WebhookRequest"))),
+            (Some(RouteBody::GraphQL), _) => Some(content(&schema_ref("GraphQLRequest"))),
+            _ => None,
+        };
+        if let Some(body_content) = body_content {
+            operation.insert("requestBody".to_string(), json!({
+                "required": true,
+                "content": body_content,
+            }));
+        }
+
+        let success = match route.response {
+            Some("Csv") => csv_content(),
+            Some("EventStream") => json!({ "text/event-stream": { "schema": { "type": "string" } } }),
+            // Lists can also be streamed, with each line holding one row
+            Some(name) if name.ends_with("List") => {
+                let mut list = content(&schema_ref(name));
+                if let (Some(list), Some(row)) = (list.as_object_mut(), name.strip_suffix("List")) {
+                    list.insert(NDJSON_MIME.to_string(), json!({ "schema": schema_ref(row) }));
+                }
+                list
+            },
+            Some(name) => content(&schema_ref(name)),
+            None => content(&json!({ "type": "string" })),
+        };
+        operation.insert("responses".to_string(), json!({
+            (route.status.to_string()): {
+                "description": "Success",
+                "content": success,
+            },
+            "default": {
+                "description": "Error",
+                "content": {
+                    "application/json": { "schema": { "$ref": "#/components/schemas/ErrorMessage" } },
+                    "application/problem+json": { "schema": { "$ref": "#/components/schemas/Problem" } },
+                },
+            },
+        }));
+
+        let path = paths
+            .entry(route.path.to_string())
+            .or_insert_with(|| json!({}));
+        if let Some(path) = path.as_object_mut() {
+            path.insert(route.method.to_string(), Value::Object(operation));
+        }
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "description": "A token listed in the server config." },
+            },
+        },
+    })
+}
+
+/// Points at a schema in the document's components.
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Describes a CSV file with a header row.
+fn csv_content() -> Value {
+    json!({ "text/csv": { "schema": { "type": "string" } } })
+}
+
+/// Lists the same schema under every supported media type.
+fn content(schema: &Value) -> Value {
+    let mut content = Map::new();
+    for media_type in MEDIA_TYPES {
+        content.insert(media_type.to_string(), json!({ "schema": schema }));
+    }
+    Value::Object(content)
+}
+
+/// Describes a query parameter named in `RouteDoc::query`.
+///
+/// Parameters not described here are documented as plain strings.
+fn query_parameter(name: &str) -> Value {
+    let (description, schema) = match name {
+        "limit" => (
+            format!("The most rows to return, {} by default and at most {}.", DEFAULT_LIMIT, MAX_LIMIT),
+            json!({ "type": "integer", "minimum": 0, "maximum": MAX_LIMIT }),
+        ),
+        "offset" => ("The number of rows to skip.".to_string(), json!({ "type": "integer", "minimum": 0 })),
+        "fields" => ("A comma-separated list of the fields to return, e.g. name,type.".to_string(), json!({ "type": "string" })),
+        "filter" => ("A condition on the rows, e.g. type in ('Admin', 'Mod') and registered >= '2023-01-01'.".to_string(), json!({ "type": "string" })),
+        "embed" => ("A comma-separated list of child tables or foreign key columns whose rows are inlined under _embedded.".to_string(), json!({ "type": "string" })),
+        "include_deleted" => ("Whether to include soft-deleted rows (admin only).".to_string(), json!({ "type": "boolean", "default": false })),
+        "q" => ("The words to search the text fields for.".to_string(), json!({ "type": "string" })),
+        "group_by" => ("A comma-separated list of the fields to group rows by.".to_string(), json!({ "type": "string" })),
+        "metrics" => ("A comma-separated list of metrics, such as count, count:field, sum:field, avg:field, min:field or max:field.".to_string(), json!({ "type": "string", "default": "count" })),
+        "mode" => ("Whether operations are all-or-nothing or applied one by one.".to_string(), json!({ "type": "string", "enum": ["atomic", "best_effort"], "default": "atomic" })),
+        "table" => ("Only include changes to this table.".to_string(), json!({ "type": "string" })),
+        "id" => ("Only include changes to the row with this id.".to_string(), json!({ "type": "integer", "minimum": 0 })),
+        "state" => ("Only include deliveries in this state.".to_string(), json!({ "type": "string", "enum": ["pending", "delivered", "dead"] })),
+        _ => (String::new(), json!({ "type": "string" })),
+    };
+
+    let mut parameter = json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "schema": schema,
+    });
+    if let (Some(parameter), false) = (parameter.as_object_mut(), description.is_empty()) {
+        parameter.insert("description".to_string(), json!(description));
+    }
+    parameter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_documented_route_is_in_the_spec() {
+        let spec = gen_openapi();
+        for route in ROUTE_DOCS {
+            let operation = &spec["paths"][route.path][route.method];
+            assert!(operation.is_object(), "{} {} is missing", route.method, route.path);
+            assert_eq!(operation["summary"], route.summary);
+        }
+    }
+
+    #[test]
+    fn query_parameters_are_described() {
+        let spec = gen_openapi();
+        for route in ROUTE_DOCS {
+            let parameters = spec["paths"][route.path][route.method]["parameters"].as_array().cloned().unwrap_or_default();
+            for name in route.query {
+                let parameter = parameters
+                    .iter()
+                    .find(|parameter| parameter["in"] == "query" && parameter["name"] == *name)
+                    .unwrap_or_else(|| panic!("{} {} does not list ?{}", route.method, route.path, name));
+                assert!(parameter["description"].is_string(), "?{} is not described", name);
+            }
+        }
+
+        let list = &spec["paths"]["/user"]["get"]["parameters"];
+        let names: Vec<&str> = list.as_array().unwrap().iter().filter_map(|parameter| parameter["name"].as_str()).collect();
+        assert_eq!(names, ["limit", "offset", "fields", "filter", "include_deleted"]);
+    }
+}
@@ -2,131 +2,116 @@ use std::collections::HashMap;
 use warp::Rejection;
 use warp::Reply;
 use warp::Filter;
+use sqlx::MySqlConnection;
 use rustract::types::DataTypeValue;
 
 use crate::DB_DESIGN;
 use crate::ErrorType;
 use crate::AppError;
 use crate::Check;
-use crate::routes::respond;
-use crate::routes::with_json_body;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond_row;
+use crate::conditional::check_if_match;
+use crate::conditional::with_if_match;
+use crate::routes::with_body;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::audit;
+use crate::relations;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
 
 // PATCH <domain>/user/#
 /// A function that returns a warp route for updating user info.
+/// 
+/// An `If-Match` header is checked against the user's current `ETag`.
 pub(crate) fn patch_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("user" / u32)
         .and(warp::patch())
-        .and(with_json_body())
+        .and(check_accept())
+        .and(with_body())
         .and_then(patch_extract)
+        .and(with_if_match())
+        .and(with_audit())
         .and_then(patch_insert)
+        .and(with_format())
         .and_then(patch_success)
 }
 
-/// Uses the fields to create a PATCH query.
+/// Uses the fields to make an SQL UPDATE query.
 /// 
 /// The `req` variable now has some of the data specified by the `FieldDesign`.
-/// Since not all of it is included, error handling is more important here.
-/// 
-/// You could also use the global's field info to avoid possible developer error!
-async fn patch_insert(req: (u32, HashMap<String, DataTypeValue>)) -> Result<String, warp::reject::Rejection> {
-    // These are all the possible parameters that can be updated
-    let id = req.0;
-    let mut body = req.1;
-    let mut name: Option<String> = None;
-    let mut email: Option<String> = None;
-    let mut registered: Option<String> = None;
-    let mut type_field: Option<u32> = None;
-
-    // name
-    if let Some(value) = body.remove("name") {
-        name = Some(match value {
-            DataTypeValue::String(data) => data,
-            _ => Err(AppError {
-                err_type: ErrorType::Internal,
-                message: format!("err: wrong type; expected String, found other; JSON: \"{}\"", value),
-            })?
-        });
-    }
+/// The row is locked while `If-Match` is checked, so concurrent updates cannot overwrite each other.
+async fn patch_insert(req: (u32, HashMap<String, DataTypeValue>), if_match: Option<String>, context: AuditContext) -> Result<Row, warp::reject::Rejection> {
+    let (id, body) = req;
+    let values = db::sql_values("user", body)?;
 
-    // email
-    if let Some(value) = body.remove("email") {
-        email = Some(match value {
-            DataTypeValue::String(data) => data,
-            _ => Err(AppError {
-                err_type: ErrorType::Internal,
-                message: format!("err: wrong type; expected String, found other; JSON: \"{}\"", value),
-            })?
-        });
-    }
+    let mut tx = db::begin().await?;
+    let user = update_user(&mut tx, id, values, &if_match, &context).await?;
+    tx.commit().await.map_err(AppError::from)?;
+    context.publish();
 
-    // registered
-    if let Some(value) = body.remove("registered") {
-        registered = Some(match value {
-            DataTypeValue::String(data) => data,
-            _ => Err(AppError {
-                    err_type: ErrorType::Internal,
-                    message: format!("err: wrong type; expected String, found other; JSON: \"{}\"", value),
-                })?
-        });
-    }
+    Ok(user)
+}
 
-    // type
-    if let Some(value) = body.remove("type") {
-        type_field = Some(match value {
-            DataTypeValue::Enum(data) => data,
-            _ => return Err(AppError {
-                err_type: ErrorType::Internal,
-                message: format!("err: wrong type; expected Enum, found other; JSON: \"{}\"", value)
-            })?
-        });
-    }
+/// Updates the given columns of a user and records the change in the audit log.
+pub(crate) async fn update_user(
+    conn: &mut MySqlConnection,
+    id: u32,
+    values: Vec<(String, db::SqlValue)>,
+    if_match: &Option<String>,
+    context: &AuditContext,
+) -> Result<Row, AppError> {
+    modify_row(conn, "user", id, values, if_match, context).await
+}
 
-    // An SQL query can be made here that safely updates any verified data
-    // You could also error if nothing but the id is included
-    let mut query_string = format!("Updated User: {{ id: {}, ", id);
-    if let Some(value) = name {
-        query_string += "name: ";
-        query_string += value.as_str();
-        query_string += ",";
-    }
-    if let Some(value) = email {
-        query_string += "email: ";
-        query_string += value.as_str();
-        query_string += ",";
-    }
-    if let Some(value) = registered {
-        query_string += "registered: ";
-        query_string += value.as_str();
-        query_string += ",";
-    }
-    if let Some(value) = type_field {
-        query_string += "type: ";
-        query_string += value.to_string().as_str();
-    }
-    print!(
-        "{}",
-        query_string
-    );
+/// Updates the given columns of a row of any table and records the change in the audit log.
+/// 
+/// The row is locked while `If-Match` is checked, and the updated row is returned.
+/// Rows referenced by changed foreign keys must exist, see `relations::check_references`.
+pub(crate) async fn modify_row(
+    conn: &mut MySqlConnection,
+    table: &str,
+    id: u32,
+    values: Vec<(String, db::SqlValue)>,
+    if_match: &Option<String>,
+    context: &AuditContext,
+) -> Result<Row, AppError> {
+    let current = db::fetch_row(conn, table, id, true).await?;
+    check_if_match(&current, if_match)?;
+    relations::check_references(conn, table, &values).await?;
+    db::update_row(conn, table, id, values).await?;
+    let row = db::fetch_row(conn, table, id, false).await?;
+    audit::record(conn, context, table, id, audit::Action::Update, Some(&current), Some(&row)).await?;
 
-    // The id is passed on for the hello world filter to consume
-    Ok(req.0.to_string())
+    Ok(row)
 }
 
 /// Extracts the data from the request body and verifies it in the process.
 /// 
 /// This function has custom requirements, so it is best used for PATCH requests.
-async fn patch_extract(id: u32, body: serde_json::Value) -> Result<(u32, HashMap<String, DataTypeValue>), warp::reject::Rejection> {
+pub(crate) async fn patch_extract(id: u32, body: serde_json::Value) -> Result<(u32, HashMap<String, DataTypeValue>), warp::reject::Rejection> {
+    Ok((id, extract_changes("user", body)?))
+}
+
+/// Extracts the changed fields of a row of any table from a JSON object, like `patch_extract` does for users.
+///
+/// The primary key cannot be changed, and is ignored.
+pub(crate) fn extract_changes(table: &str, body: serde_json::Value) -> Result<HashMap<String, DataTypeValue>, AppError> {
     // The map this function will extract from the JSON body
     let mut map: HashMap<String, DataTypeValue> = HashMap::new();
+    let primary_key = db::primary_key(table);
 
     // Checks to make sure the data exists/is structured properly
     if let Some(data_map) = body.as_object() {
-        for key in DB_DESIGN.table("user").check()?.fields.keys() {
+        for key in DB_DESIGN.table(table).check()?.fields.keys() {
             let field = DB_DESIGN
-                .table("user").check()?
+                .table(table).check()?
                 .field(key).check()?;
 
-            if field.field_design_title == "id".to_string() {
+            if field.field_design_title == primary_key {
                 continue;
             }
 
@@ -139,31 +124,31 @@ async fn patch_extract(id: u32, body: serde_json::Value) -> Result<(u32, HashMap
                         );
                     },
                     Err(error) => {
-                        Err(AppError {
+                        return Err(AppError {
                             err_type: ErrorType::BadRequest,
-                            message: format!("field {} is not formatted properly: {}", &field.field_design_title, error.to_string())
-                        })?
+                            message: format!("field {} is not formatted properly: {}", &field.field_design_title, error),
+                            field: Some(field.field_design_title.to_string())
+                        });
                     }
                 }
             }
         }
 
-        Ok((id, map))
+        Ok(map)
     } else {
         Err(AppError {
             err_type: ErrorType::BadRequest,
-            message: format!("failed to parse JSON as object, JSON: \"{}\" (err: body should be a map)", body.to_string()),
-        })?
+            message: format!("failed to parse JSON as object, JSON: \"{}\" (err: body should be a map)", body),
+            field: None,
+        })
     }
 }
 
-/// Replies with a success code and PATCH-related message.
-async fn patch_success(user_name: String) -> Result<impl Reply, Rejection> {
-    respond(
-        Ok(format!(
-            "Welcome, User #{}! If this was hooked up to a database, your information would be changed.",
-            user_name)
-        ),
-        warp::http::StatusCode::ACCEPTED
+/// Replies with the updated user and its new `ETag`.
+async fn patch_success(user: Row, format: Format) -> Result<impl Reply, Rejection> {
+    respond_row(
+        Ok(user),
+        warp::http::StatusCode::OK,
+        format
     )
 }
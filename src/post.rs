@@ -1,102 +1,109 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use warp::Rejection;
 use warp::Reply;
 use warp::Filter;
+use sqlx::MySqlConnection;
 use rustract::types::DataTypeValue;
 
 use crate::DB_DESIGN;
 use crate::ErrorType;
 use crate::AppError;
 use crate::Check;
-use crate::routes::respond;
-use crate::routes::with_json_body;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond_row;
+use crate::routes::with_body;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::audit;
+use crate::relations;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
+use crate::idempotency;
+use crate::idempotency::with_idempotency_key;
 
 // POST <domain>/user
 /// A function that returns a warp route for adding a new user.
 pub(crate) fn post_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("user")
         .and(warp::post())
-        .and(with_json_body())
+        .and(check_accept())
+        .and(with_body())
         .and_then(post_extract)
+        .and(with_audit())
+        .and(with_idempotency_key())
         .and_then(post_insert)
+        .and(with_format())
         .and_then(post_success)
 }
 
-/// Uses the fields to create a POST query.
+/// Uses the fields to make an SQL INSERT query.
 /// 
 /// The `req` variable now has all the data specified by the `FieldDesign`.
-/// Nothing in this section will error unless the developer uses the wrong type here,
-/// but error handling is included for that instance.
-/// 
-/// Alternatively, you could use the global's field info to avoid possible developer error!
-async fn post_insert(mut req: HashMap<String, DataTypeValue>) -> Result<String, warp::reject::Rejection> {
-    // name
-    let value = req.remove("name").check()?;
-    let name = match value {
-        DataTypeValue::String(data) => data,
-        _ => Err(AppError {
-            err_type: ErrorType::Internal,
-            message: format!("err: wrong type; expected String, found other; JSON: \"{}\"", value),
-        })?
-    };
+/// The global's field info is used to bind each value, so no fields are hard-coded here.
+/// The inserted row is read back so that generated fields are included in the reply and the audit log.
+/// If an `Idempotency-Key` is sent, the row is stored with it and returned again for retries.
+async fn post_insert(req: HashMap<String, DataTypeValue>, context: AuditContext, key: Option<String>) -> Result<Row, warp::reject::Rejection> {
+    let values: BTreeMap<&String, String> = req.iter().map(|(name, value)| (name, value.to_string())).collect();
+    let fingerprint = idempotency::fingerprint("POST /user", &values)?;
 
-    // email
-    let value = req.remove("email").check()?;
-    let email = match value {
-        DataTypeValue::String(data) => data,
-        _ => Err(AppError {
-            err_type: ErrorType::Internal,
-            message: format!("err: wrong type; expected String, found other; JSON: \"{}\"", value),
-        })?
-    };
+    let mut tx = db::begin().await?;
+    if let Some(key) = &key {
+        if let Some(user) = idempotency::find(&mut tx, &context, key, &fingerprint).await? {
+            return Ok(user);
+        }
+    }
+    let user = create_user(&mut tx, req, &context).await?;
+    if let Some(key) = &key {
+        idempotency::save(&mut tx, &context, key, &fingerprint, &user).await?;
+    }
+    tx.commit().await.map_err(AppError::from)?;
+    context.publish();
 
-    // registered
-    let value = req.remove("registered");
-    let registered: Option<String> = if let Some(value) = value {
-            match value {
-                DataTypeValue::String(data) => Some(data),
-                _ => Err(AppError {
-                        err_type: ErrorType::Internal,
-                        message: format!("err: wrong type; expected String, found other; JSON: \"{}\"", value),
-                    })?
-            }
-    } else { None };
+    Ok(user)
+}
 
-    // type
-    let value = req.remove("type").check()?;
-    let type_field = match value {
-        DataTypeValue::Enum(data) => data,
-        _ => return Err(AppError {
-            err_type: ErrorType::Internal,
-            message: format!("err: wrong type; expected Enum, found other; JSON: \"{}\"", value)
-        })?
-    };
+/// Inserts a user extracted by `post_extract` and records it in the audit log.
+pub(crate) async fn create_user(conn: &mut MySqlConnection, req: HashMap<String, DataTypeValue>, context: &AuditContext) -> Result<Row, AppError> {
+    create_row(conn, "user", req, context).await
+}
 
-    // An SQL query can be made here that safely inserts the verified data
-    let mut output = format!("Found User: {{ name: {}, email: {}, ", name, email);
-    if let Some(v) = registered { output += format!("registered: {}, ", v).as_str() };
-    output += format!("type: {} }}", type_field).as_str();
-    println!(
-        "{}",
-        output
-    );
+/// Inserts a row extracted by `extract_row` into any table and records it in the audit log.
+///
+/// Rows referenced by foreign keys must exist, see `relations::check_references`.
+pub(crate) async fn create_row(conn: &mut MySqlConnection, table: &str, req: HashMap<String, DataTypeValue>, context: &AuditContext) -> Result<Row, AppError> {
+    let values = db::sql_values(table, req)?;
+    relations::check_references(conn, table, &values).await?;
+    let id = db::insert_row(conn, table, values).await?;
+    let id = u32::try_from(id).map_err(|_| AppError::new(
+        ErrorType::Internal,
+        format!("err: inserted id {} does not fit in a u32", id)
+    ))?;
+    let row = db::fetch_row(conn, table, id, false).await?;
+    audit::record(conn, context, table, id, audit::Action::Create, None, Some(&row)).await?;
 
-    // The name is passed on for the hello world filter to consume
-    Ok(name.to_string())
+    Ok(row)
 }
 
 /// Extracts the data from the request body and verifies it in the process.
 /// 
 /// This function will require all required fields, so it is best used for POST requests.
-async fn post_extract(body: serde_json::Value) -> Result<HashMap<String, DataTypeValue>, warp::reject::Rejection> {
+pub(crate) async fn post_extract(body: serde_json::Value) -> Result<HashMap<String, DataTypeValue>, warp::reject::Rejection> {
+    Ok(extract_row("user", body)?)
+}
+
+/// Extracts a new row of any table from a JSON object, like `post_extract` does for users.
+pub(crate) fn extract_row(table: &str, body: serde_json::Value) -> Result<HashMap<String, DataTypeValue>, AppError> {
     // The map this function will extract from the JSON body
     let mut map: HashMap<String, DataTypeValue> = HashMap::new();
 
     // Checks to make sure the data exists/is structured properly
     if let Some(data_map) = body.as_object() {
-        for key in DB_DESIGN.table("user").check()?.fields.keys() {
+        for key in DB_DESIGN.table(table).check()?.fields.keys() {
             let field = DB_DESIGN
-                .table("user").check()?
+                .table(table).check()?
                 .field(key).check()?;
 
             if let Some(data) = data_map.get(&field.field_design_title) {
@@ -108,35 +115,41 @@ async fn post_extract(body: serde_json::Value) -> Result<HashMap<String, DataTyp
                         );
                     },
                     Err(error) => {
-                        Err(AppError {
+                        return Err(AppError {
                             err_type: ErrorType::BadRequest,
-                            message: format!("field {} is not formatted properly: {}", &field.field_design_title, error.to_string())
-                        })?
+                            message: format!("field {} is not formatted properly: {}", &field.field_design_title, error),
+                            field: Some(field.field_design_title.to_string())
+                        });
                     }
                 }
             } else if field.required && !field.generated {
-                Err(AppError {
+                return Err(AppError {
                     err_type: ErrorType::BadRequest,
                     message: format!("field {} is listed as required, but was not included in the request body", &field.field_design_title),
-                })?
+                    field: Some(field.field_design_title.to_string()),
+                });
             }
         }
         Ok(map)
     } else {
         Err(AppError {
             err_type: ErrorType::BadRequest,
-            message: format!("failed to parse JSON as object, JSON: \"{}\" (err: body should be a map)", body.to_string()),
-        })?
+            message: format!("failed to parse JSON as object, JSON: \"{}\" (err: body should be a map)", body),
+            field: None,
+        })
     }
 }
 
-/// Replies with a success code and POST-related message.
-async fn post_success(user_name: String) -> Result<impl Reply, Rejection> {
-    respond(
-        Ok(format!(
-            "Welcome, {}! If this was hooked up to a database, you would be added.",
-            user_name)
-        ),
-        warp::http::StatusCode::ACCEPTED
-    )
+/// Replies with the created user, along with its location and `ETag`.
+async fn post_success(user: Row, format: Format) -> Result<impl Reply, Rejection> {
+    let location = format!("/user/{}", user.get(&db::primary_key("user")).unwrap_or(&serde_json::Value::Null));
+    Ok(warp::reply::with_header(
+        respond_row(
+            Ok(user),
+            warp::http::StatusCode::CREATED,
+            format
+        )?,
+        warp::http::header::LOCATION,
+        location
+    ))
 }
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use rustract::types::DataTypeValue;
+
+use crate::DB_DESIGN;
+use crate::AppError;
+use crate::Check;
+use crate::db;
+use crate::db::Row;
+use crate::db::SqlValue;
+use crate::post::post_extract;
+use crate::patch::update_user;
+use crate::routes::respond_row;
+use crate::routes::with_body;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
+use crate::conditional::with_if_match;
+
+// PUT <domain>/user/#
+/// A function that returns a warp route for replacing user info.
+/// 
+/// The body is validated like a POST body, and an `If-Match` header is checked against the user's current `ETag`.
+pub(crate) fn put_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / u32)
+        .and(warp::put())
+        .and(check_accept())
+        .and(with_body())
+        .and_then(put_extract)
+        .and(with_if_match())
+        .and(with_audit())
+        .and_then(put_insert)
+        .and(with_format())
+        .and_then(put_success)
+}
+
+/// Extracts the data from the request body, requiring every required field.
+async fn put_extract(id: u32, body: serde_json::Value) -> Result<(u32, HashMap<String, DataTypeValue>), warp::reject::Rejection> {
+    let mut map = post_extract(body).await?;
+
+    // The id comes from the path, so it cannot be replaced
+    map.remove(&db::primary_key("user"));
+    Ok((id, map))
+}
+
+/// Uses the fields to make an SQL UPDATE query that replaces the whole row.
+/// 
+/// Fields that were left out of the body are set to NULL.
+async fn put_insert(req: (u32, HashMap<String, DataTypeValue>), if_match: Option<String>, context: AuditContext) -> Result<Row, warp::reject::Rejection> {
+    let (id, body) = req;
+    let primary_key = db::primary_key("user");
+    let mut values = db::sql_values("user", body)?;
+
+    for (key, field) in DB_DESIGN.tables.get("user").check()?.fields.iter() {
+        if *key != primary_key && !field.generated && !values.iter().any(|(name, _)| name == key) {
+            values.push((key.to_string(), SqlValue::Null));
+        }
+    }
+
+    let mut tx = db::begin().await?;
+    let user = update_user(&mut tx, id, values, &if_match, &context).await?;
+    tx.commit().await.map_err(AppError::from)?;
+    context.publish();
+
+    Ok(user)
+}
+
+/// Replies with the replaced user and its new `ETag`.
+async fn put_success(user: Row, format: Format) -> Result<impl Reply, Rejection> {
+    respond_row(
+        Ok(user),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
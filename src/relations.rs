@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use serde_json::Value;
+use sqlx::MySqlConnection;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::ErrorType;
+use crate::AppError;
+use crate::db;
+use crate::db::Condition;
+use crate::db::Row;
+use crate::db::SqlValue;
+use crate::dump::DUMP_INFO;
+use crate::dump::ForeignKeyInfo;
+use crate::dump::TableInfo;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::list::ListQuery;
+use crate::list::DEFAULT_LIMIT;
+use crate::list::MAX_LIMIT;
+use crate::list::parse_list_query;
+use crate::auth::Actor;
+use crate::auth::with_actor;
+
+/// The most child rows inlined for each embedded table.
+const EMBED_LIMIT: u32 = 100;
+
+/// The key embedded rows are put under, so that they cannot clash with fields.
+pub(crate) const EMBEDDED_KEY: &str = "_embedded";
+
+/// The sub-routes of a user, such as `/user/{id}/restore`, which are not child tables.
+///
+/// `list_related` leaves these to their own routes, so a child table cannot be listed under these names.
+const SUB_ROUTES: [&str; 1] = ["restore"];
+
+/// Returns the single-column foreign keys of other tables in the dump that point at a table's primary key, by child table name.
+///
+/// A child table can reference the parent more than once, e.g. with `created_by` and `updated_by`.
+/// Composite foreign keys are not supported, and are left out.
+fn children<'a>(dump: &'a HashMap<String, TableInfo>, table: &str) -> Vec<(&'a str, Vec<&'a ForeignKeyInfo>)> {
+    let primary_key = dump
+        .get(table)
+        .and_then(|info| info.primary_key.first().cloned())
+        .unwrap_or_else(|| "id".to_string());
+    let mut children: Vec<(&str, Vec<&ForeignKeyInfo>)> = Vec::new();
+    for (child, info) in dump.iter() {
+        let keys: Vec<&ForeignKeyInfo> = info.foreign_keys
+            .iter()
+            .filter(|key| key.references_table == table && key.columns.len() == 1 && key.references_columns == [primary_key.as_str()])
+            .collect();
+        if !keys.is_empty() {
+            children.push((child.as_str(), keys));
+        }
+    }
+    children.sort_by(|a, b| a.0.cmp(b.0));
+    children
+}
+
+/// Returns the single-column foreign keys of a table in the dump, which point at the rows it belongs to.
+fn parents<'a>(dump: &'a HashMap<String, TableInfo>, table: &str) -> Vec<&'a ForeignKeyInfo> {
+    dump
+        .get(table)
+        .map(|info| info.foreign_keys.iter().filter(|key| key.columns.len() == 1).collect())
+        .unwrap_or_default()
+}
+
+/// Returns a condition matching the rows of a child table that reference the given parent row through any of the keys.
+fn child_condition(keys: &[&ForeignKeyInfo], id: u32) -> Condition {
+    let columns: Vec<String> = keys.iter().map(|key| format!("`{}` = ?", key.columns[0])).collect();
+    Condition {
+        sql: format!("({})", columns.join(" OR ")),
+        values: vec![SqlValue::String(id.to_string()); keys.len()],
+    }
+}
+
+/// Checks that the rows referenced by the foreign keys among the values exist, or errors with `422 Unprocessable Entity`.
+///
+/// Soft-deleted rows count as missing. The referenced rows are locked until the surrounding transaction ends,
+/// so that they cannot be deleted before the values are written.
+/// Keys that are not in the values, or set to null, are not checked.
+pub(crate) async fn check_references(conn: &mut MySqlConnection, table: &str, values: &[(String, SqlValue)]) -> Result<(), AppError> {
+    let info = match DUMP_INFO.get(table) {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+
+    for key in info.foreign_keys.iter() {
+        let (sql, bound) = match reference_query(key, values) {
+            Some(query) => query,
+            None => continue,
+        };
+        let mut query = sqlx::query(&sql);
+        for value in bound.iter() {
+            query = query.bind(*value);
+        }
+        if query.fetch_optional(&mut *conn).await?.is_none() {
+            return Err(AppError {
+                err_type: ErrorType::UnprocessableEntity,
+                message: format!(
+                    "field {} references {} #{}, which does not exist",
+                    key.columns.join(", "),
+                    key.references_table,
+                    bound.iter().map(|value| value.as_str()).collect::<Vec<&str>>().join(", ")
+                ),
+                field: key.columns.first().cloned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the query that finds the row referenced by a foreign key, and the values to bind to it.
+///
+/// Returns `None` if any column of the key is not among the values, or set to null.
+fn reference_query<'a>(key: &ForeignKeyInfo, values: &'a [(String, SqlValue)]) -> Option<(String, Vec<&'a String>)> {
+    let bound: Vec<&String> = key.columns
+        .iter()
+        .filter_map(|column| match values.iter().find(|(name, _)| name == column) {
+            Some((_, SqlValue::String(value))) => Some(value),
+            _ => None,
+        })
+        .collect();
+    if bound.len() != key.columns.len() {
+        return None;
+    }
+
+    let conditions: Vec<String> = key.references_columns.iter().map(|column| format!("`{}` = ?", column)).collect();
+    let sql = format!(
+        "SELECT 1 FROM `{}` WHERE {}{} LIMIT 1 LOCK IN SHARE MODE",
+        key.references_table,
+        conditions.join(" AND "),
+        if db::soft_deletes(&key.references_table) { format!(" AND `{}` IS NULL", db::SOFT_DELETE_COLUMN) } else { String::new() }
+    );
+    Some((sql, bound))
+}
+
+/// Parses an `embed` parameter, a comma-separated list of related rows to inline.
+///
+/// Each name is either a child table, which inlines the rows referencing this one,
+/// or a foreign key column of this table, which inlines the row it references.
+pub(crate) fn parse_embed(table: &str, value: Option<&str>) -> Result<Vec<String>, AppError> {
+    parse_embed_in(&DUMP_INFO, table, value)
+}
+
+/// Parses an `embed` parameter against the foreign keys of a dump, see `parse_embed`.
+fn parse_embed_in(dump: &HashMap<String, TableInfo>, table: &str, value: Option<&str>) -> Result<Vec<String>, AppError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    let children = children(dump, table);
+    let parents = parents(dump, table);
+
+    let mut embed: Vec<String> = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        let known = children.iter().any(|(child, _)| *child == name)
+            || parents.iter().any(|key| key.columns[0] == name);
+        if !known {
+            let mut names: Vec<&str> = children.iter().map(|(child, _)| *child).collect();
+            names.extend(parents.iter().map(|key| key.columns[0].as_str()));
+            return Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: if names.is_empty() {
+                    format!("embed is not supported, since no foreign keys relate {} to other tables", table)
+                } else {
+                    format!("embed should be a comma-separated list of {}, but \"{}\" is not one", names.join(", "), name)
+                },
+                field: Some("embed".to_string()),
+            });
+        }
+        if !embed.iter().any(|embedded| embedded == name) {
+            embed.push(name.to_string());
+        }
+    }
+    Ok(embed)
+}
+
+/// Extracts the `?embed=` parameter of a single-row route, see `parse_embed`.
+///
+/// Other query parameters are ignored.
+pub(crate) fn with_embed(table: &'static str) -> impl Filter<Extract = (Vec<String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and_then(move |query: HashMap<String, String>| async move {
+            parse_embed(table, query.get("embed").map(String::as_str)).map_err(AppError::into_warp)
+        })
+}
+
+/// Reads the related rows named by `parse_embed` and puts them in the row under `_embedded`.
+///
+/// Child tables become arrays of at most `EMBED_LIMIT` rows, and foreign keys the referenced row, or null.
+/// Soft-deleted related rows are left out unless `include_deleted` is set.
+pub(crate) async fn embed(conn: &mut MySqlConnection, table: &str, id: u32, embed: &[String], include_deleted: bool, row: &mut Row) -> Result<(), AppError> {
+    if embed.is_empty() {
+        return Ok(());
+    }
+    let children = children(&DUMP_INFO, table);
+    let parents = parents(&DUMP_INFO, table);
+
+    let mut embedded = Row::new();
+    for name in embed {
+        if let Some((child, keys)) = children.iter().find(|(child, _)| child == name) {
+            let rows = db::fetch_rows(&mut *conn, child, None, vec![child_condition(keys, id)], include_deleted, EMBED_LIMIT, 0).await?;
+            embedded.insert(name.to_string(), Value::Array(rows.into_iter().map(Value::Object).collect()));
+        } else if let Some(key) = parents.iter().find(|key| &key.columns[0] == name) {
+            // The key is read with a subquery, since it may not be among the requested fields
+            let sql = format!(
+                "SELECT {} FROM `{}` WHERE `{}` = (SELECT `{}` FROM `{}` WHERE `{}` = ?){}",
+                db::projection(&key.references_table, None),
+                key.references_table,
+                key.references_columns[0],
+                key.columns[0],
+                table,
+                db::primary_key(table),
+                if include_deleted || !db::soft_deletes(&key.references_table) { String::new() } else { format!(" AND `{}` IS NULL", db::SOFT_DELETE_COLUMN) }
+            );
+            let parent = match sqlx::query(&sql).bind(id).fetch_optional(&mut *conn).await? {
+                Some(parent) => Value::Object(db::row_to_json(&parent)?),
+                None => Value::Null,
+            };
+            embedded.insert(name.to_string(), parent);
+        }
+    }
+    row.insert(EMBEDDED_KEY.to_string(), Value::Object(embedded));
+    Ok(())
+}
+
+// GET <domain>/user/#/<child_table>
+/// A function that returns a warp route for listing the rows of another table that reference a user.
+///
+/// The child table is any table with a foreign key to `user.id`, and its rows can be filtered and paged like `list_user`.
+/// The user must exist, so a missing user is `404 Not Found` rather than an empty list.
+/// Names in `SUB_ROUTES` are left to their own routes, so that e.g. `GET /user/{id}/restore` is `405 Method Not Allowed`.
+pub(crate) fn list_related() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / u32 / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(related_extract)
+        .and_then(related_retrieve)
+        .and(with_format())
+        .and_then(related_success)
+}
+
+/// Checks that the table references users, and parses the query against the child table's fields.
+async fn related_extract(id: u32, child: String, query: HashMap<String, String>, actor: Option<Actor>) -> Result<(u32, String, ListQuery), warp::reject::Rejection> {
+    if SUB_ROUTES.contains(&child.as_str()) {
+        return Err(warp::reject::not_found());
+    }
+    let keys = children(&DUMP_INFO, "user")
+        .into_iter()
+        .find(|(name, _)| *name == child)
+        .map(|(_, keys)| keys)
+        .ok_or_else(|| AppError::new(ErrorType::NotFound, format!("{} is not a table that references user", child)))?;
+
+    let mut list_query = parse_list_query(&child, query, &actor)?;
+    list_query.filters.push(child_condition(&keys, id));
+    Ok((id, child, list_query))
+}
+
+/// Checks that the user exists, then reads the matching child rows ordered by their primary key.
+async fn related_retrieve(req: (u32, String, ListQuery)) -> Result<Vec<Row>, warp::reject::Rejection> {
+    let (id, child, query) = req;
+    let mut conn = db::acquire().await?;
+    let primary_key = [db::primary_key("user")];
+    db::fetch_fields(&mut conn, "user", Some(&primary_key), id, query.include_deleted).await?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    Ok(db::fetch_rows(&mut conn, &child, query.fields.as_deref(), query.filters, query.include_deleted, limit, query.offset).await?)
+}
+
+/// Replies with the child rows.
+async fn related_success(rows: Vec<Row>, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(rows),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dump::parse_dump;
+    use crate::dump::tests::RELATED_DUMP;
+
+    #[test]
+    fn children_are_tables_with_single_column_keys_to_the_primary_key() {
+        let dump = parse_dump(RELATED_DUMP);
+        let of_user = children(&dump, "user");
+        assert_eq!(of_user.len(), 1);
+        let (child, keys) = &of_user[0];
+        assert_eq!(*child, "post");
+        let columns: Vec<&str> = keys.iter().map(|key| key.columns[0].as_str()).collect();
+        assert_eq!(columns, ["author_id", "created_by", "updated_by"]);
+
+        assert!(children(&dump, "post").is_empty());
+        assert_eq!(parents(&dump, "post").len(), 3);
+        // Composite keys are left out
+        assert!(parents(&dump, "tag").is_empty());
+    }
+
+    #[test]
+    fn child_rows_match_any_of_the_keys() {
+        let dump = parse_dump(RELATED_DUMP);
+        let keys = &children(&dump, "user")[0].1;
+        let condition = child_condition(&keys[1..], 7);
+        assert_eq!(condition.sql, "(`created_by` = ? OR `updated_by` = ?)");
+        assert_eq!(condition.values, vec![SqlValue::String("7".to_string()); 2]);
+    }
+
+    #[test]
+    fn embed_names_children_and_foreign_keys() {
+        let dump = parse_dump(RELATED_DUMP);
+        assert_eq!(parse_embed_in(&dump, "user", None).unwrap(), Vec::<String>::new());
+        assert_eq!(parse_embed_in(&dump, "user", Some("post, post")).unwrap(), ["post"]);
+        assert_eq!(parse_embed_in(&dump, "post", Some("author_id,updated_by")).unwrap(), ["author_id", "updated_by"]);
+
+        let err = parse_embed_in(&dump, "post", Some("comments")).unwrap_err();
+        assert_eq!(err.message, "embed should be a comma-separated list of author_id, created_by, updated_by, but \"comments\" is not one");
+        assert_eq!(err.field.as_deref(), Some("embed"));
+
+        let err = parse_embed_in(&dump, "tag", Some("post")).unwrap_err();
+        assert_eq!(err.message, "embed is not supported, since no foreign keys relate tag to other tables");
+    }
+
+    #[test]
+    fn references_are_checked_when_every_column_is_set() {
+        let dump = parse_dump(RELATED_DUMP);
+        let key = &dump["post"].foreign_keys[0];
+        let values = vec![
+            ("author_id".to_string(), SqlValue::String("3".to_string())),
+            ("created_by".to_string(), SqlValue::Null),
+        ];
+        let (sql, bound) = reference_query(key, &values).unwrap();
+        assert_eq!(sql, "SELECT 1 FROM `user` WHERE `id` = ? LIMIT 1 LOCK IN SHARE MODE");
+        assert_eq!(bound, ["3"]);
+
+        // Null and missing keys are not checked
+        assert!(reference_query(&dump["post"].foreign_keys[1], &values).is_none());
+        assert!(reference_query(&dump["post"].foreign_keys[2], &values).is_none());
+    }
+}
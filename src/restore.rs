@@ -0,0 +1,66 @@
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::ErrorType;
+use crate::AppError;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond_row;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::audit;
+use crate::audit::AuditContext;
+use crate::audit::with_audit;
+use crate::auth::require_admin;
+
+// POST <domain>/user/#/restore
+/// A function that returns a warp route for undeleting a soft-deleted user.
+///
+/// Only admins may restore users, and the user table must be listed in `soft_delete`.
+pub(crate) fn restore_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / u32 / "restore")
+        .and(warp::post())
+        .and(check_accept())
+        .and(with_audit())
+        .and_then(restore_insert)
+        .and(with_format())
+        .and_then(restore_success)
+}
+
+/// Clears the user's `deleted_at` mark with an SQL UPDATE query.
+async fn restore_insert(id: u32, context: AuditContext) -> Result<Row, warp::reject::Rejection> {
+    require_admin(&context.actor)?;
+    if !db::soft_deletes("user") {
+        Err(AppError::new(
+            ErrorType::NotFound,
+            "user is not configured for soft deletes, so deleted users cannot be restored".to_string()
+        ))?
+    }
+
+    let mut tx = db::begin().await?;
+    let current = db::fetch_any_row(&mut tx, "user", id, true).await?;
+    if current.get(db::SOFT_DELETE_COLUMN).is_none_or(|deleted_at| deleted_at.is_null()) {
+        Err(AppError::new(
+            ErrorType::Conflict,
+            format!("user #{} is not deleted", id)
+        ))?
+    }
+    db::set_deleted(&mut tx, "user", id, false).await?;
+    let user = db::fetch_row(&mut tx, "user", id, false).await?;
+    audit::record(&mut tx, &context, "user", id, audit::Action::Restore, Some(&current), Some(&user)).await?;
+    tx.commit().await.map_err(AppError::from)?;
+    context.publish();
+
+    Ok(user)
+}
+
+/// Replies with the restored user.
+async fn restore_success(user: Row, format: Format) -> Result<impl Reply, Rejection> {
+    respond_row(
+        Ok(user),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
@@ -1,36 +1,251 @@
 use warp::Rejection;
 use warp::Reply;
 use warp::Filter;
+use warp::hyper::Body;
+use warp::hyper::body::Bytes;
+use tokio::sync::mpsc;
 
 use crate::AppError;
+use crate::format::Format;
+use crate::format::decode_body;
+use crate::db::Row;
+use crate::conditional::etag;
 use crate::post::post_user;
 use crate::get::get_user;
+use crate::list::list_user;
+use crate::search::search_user;
+use crate::aggregate::count_user;
+use crate::aggregate::aggregate_user;
+use crate::relations::list_related;
+use crate::cache::cache_stats;
+use crate::export::export_user;
+use crate::import::import_user;
 use crate::patch::patch_user;
+use crate::put::put_user;
 use crate::delete::delete_user;
+use crate::restore::restore_user;
+use crate::audit::get_audit;
+use crate::bulk::bulk_user;
+use crate::changes::ws_changes;
+use crate::events::user_events;
+use crate::webhooks::post_webhook;
+use crate::webhooks::list_webhooks;
+use crate::webhooks::delete_webhook;
+use crate::webhooks::get_deliveries;
+use crate::webhooks::retry_delivery;
+use crate::graphql::graphql;
+use crate::health::health;
+use crate::health::ready;
+use crate::openapi::openapi;
+use crate::openapi::docs;
+use crate::schema::get_schema;
+use crate::schema::get_table_schema;
 
 /// Returns the route tree to be served.
 /// 
 /// Currently, CRUD is implemented for the user table.
-pub fn gen_routes() -> impl Filter<Extract=impl Reply, Error=Rejection> + Clone  {
+/// Health and readiness probes are also served for orchestrators.
+/// Any API route added here should also be described in `ROUTE_DOCS`.
+pub fn gen_routes() -> impl Filter<Extract=(impl Reply,), Error=Rejection> + Clone  {
     post_user() // Create
         .or(get_user()) // Read
+        .or(list_user()) // Read many
+        .or(search_user()) // Full-text search
+        .or(count_user()) // Count
+        .or(aggregate_user()) // Grouped metrics
+        .or(list_related()) // Rows of other tables referencing a user
+        .or(export_user()) // Read many as CSV
+        .or(user_events()) // Live changes as Server-Sent Events
         .or(patch_user()) // Update
+        .or(put_user()) // Replace
         .or(delete_user()) // Delete
+        .or(restore_user()) // Undelete
+        .or(bulk_user()) // Batch create, update and delete
+        .or(import_user()) // Create many from CSV
+        .or(get_audit()) // Change history
+        .or(ws_changes()) // Live change feed
+        .or(post_webhook()) // Register a webhook
+        .or(list_webhooks()) // Read webhooks
+        .or(delete_webhook()) // Remove a webhook
+        .or(get_deliveries()) // Webhook delivery log
+        .or(retry_delivery()) // Resend a dead delivery
+        .or(graphql()) // GraphQL queries and mutations
+        .or(cache_stats()) // Response cache hit counts
+        .or(health()) // Liveness probe
+        .or(ready()) // Readiness probe
+        .or(openapi()) // API description
+        .or(docs()) // Swagger UI, if enabled
+        .or(get_schema()) // Schema introspection
+        .or(get_table_schema()) // JSON Schema per table
 }
 
+/// The request body a documented route expects.
+#[derive(Clone, Copy)]
+pub(crate) enum RouteBody {
+    /// A new row for the route's table, validated by `post_extract`.
+    Create,
+    /// A partial row for the route's table, validated by `patch_extract`.
+    Update,
+    /// An array of bulk operations, see `bulk_user`.
+    Bulk,
+    /// A CSV file with a header row, see `import_user`.
+    Csv,
+    /// A webhook registration, see `post_webhook`.
+    Webhook,
+    /// A GraphQL request, see `graphql`.
+    GraphQL,
+}
+
+/// Describes a route mounted in `gen_routes`, so that documentation can be generated for it.
+///
+/// Warp filters cannot be inspected, so new routes should also be added to `ROUTE_DOCS`.
+pub(crate) struct RouteDoc {
+    pub method: &'static str,
+    /// The route path, with parameters written as `{name}`.
+    pub path: &'static str,
+    pub summary: &'static str,
+    /// The table the route works with, if any.
+    pub table: Option<&'static str>,
+    /// The names of the query parameters the route takes, see `openapi::query_parameter`.
+    pub query: &'static [&'static str],
+    pub body: Option<RouteBody>,
+    /// The status code on success.
+    pub status: u16,
+    /// The component schema of the success body, `Some("Csv")` for a CSV file, `Some("EventStream")` for Server-Sent Events, or `None` for a plain message string.
+    pub response: Option<&'static str>,
+}
+
+/// The routes served by `gen_routes`, in the same order.
+pub(crate) const ROUTE_DOCS: &[RouteDoc] = &[
+    RouteDoc { method: "post", path: "/user", summary: "Create a user", table: Some("user"), query: &[], body: Some(RouteBody::Create), status: 201, response: Some("User") },
+    RouteDoc { method: "get", path: "/user/{id}", summary: "Get a user", table: Some("user"), query: &["fields", "embed", "include_deleted"], body: None, status: 200, response: Some("User") },
+    RouteDoc { method: "get", path: "/user", summary: "List users, filtered by field values", table: Some("user"), query: &["limit", "offset", "fields", "filter", "include_deleted"], body: None, status: 200, response: Some("UserList") },
+    RouteDoc { method: "get", path: "/user/search", summary: "Search the text fields of users, best match first, with highlighted snippets", table: Some("user"), query: &["q", "limit", "offset", "include_deleted"], body: None, status: 200, response: Some("UserSearchResults") },
+    RouteDoc { method: "get", path: "/user/_count", summary: "Count users, filtered like the user list", table: Some("user"), query: &["filter", "include_deleted"], body: None, status: 200, response: Some("Count") },
+    RouteDoc { method: "get", path: "/user/_aggregate", summary: "Compute metrics such as count or avg:id for users grouped by ?group_by=, filtered like the user list", table: Some("user"), query: &["group_by", "metrics", "filter", "include_deleted", "limit", "offset"], body: None, status: 200, response: Some("Aggregates") },
+    RouteDoc { method: "get", path: "/user/{id}/{table}", summary: "List the rows of a table with a foreign key to the user, filtered like the user list", table: None, query: &["limit", "offset", "fields", "filter", "include_deleted"], body: None, status: 200, response: Some("RelatedRows") },
+    RouteDoc { method: "get", path: "/user/export.csv", summary: "Export users as CSV, filtered like the user list", table: Some("user"), query: &["limit", "offset", "fields", "filter", "include_deleted"], body: None, status: 200, response: Some("Csv") },
+    RouteDoc { method: "get", path: "/user/events", summary: "Stream committed user changes as Server-Sent Events, resuming after Last-Event-ID", table: Some("user"), query: &["id"], body: None, status: 200, response: Some("EventStream") },
+    RouteDoc { method: "patch", path: "/user/{id}", summary: "Update a user", table: Some("user"), query: &[], body: Some(RouteBody::Update), status: 200, response: Some("User") },
+    RouteDoc { method: "put", path: "/user/{id}", summary: "Replace a user", table: Some("user"), query: &[], body: Some(RouteBody::Create), status: 200, response: Some("User") },
+    RouteDoc { method: "delete", path: "/user/{id}", summary: "Delete a user", table: Some("user"), query: &[], body: None, status: 200, response: Some("User") },
+    RouteDoc { method: "post", path: "/user/{id}/restore", summary: "Restore a soft-deleted user (admin only)", table: Some("user"), query: &[], body: None, status: 200, response: Some("User") },
+    RouteDoc { method: "post", path: "/user/_bulk", summary: "Create, update and delete many users (?mode=atomic or best_effort)", table: Some("user"), query: &["mode"], body: Some(RouteBody::Bulk), status: 200, response: Some("BulkResponse") },
+    RouteDoc { method: "post", path: "/user/import", summary: "Import users from CSV", table: Some("user"), query: &[], body: Some(RouteBody::Csv), status: 200, response: Some("ImportReport") },
+    RouteDoc { method: "get", path: "/audit", summary: "List recorded changes, newest first (admin only)", table: None, query: &["table", "id", "limit", "offset"], body: None, status: 200, response: Some("AuditLog") },
+    RouteDoc { method: "get", path: "/ws/changes", summary: "Open a WebSocket feed of committed changes, filtered by ?table= and ?id=", table: None, query: &["table", "id"], body: None, status: 101, response: Some("Change") },
+    RouteDoc { method: "post", path: "/webhooks", summary: "Register a webhook for committed changes (admin only)", table: None, query: &[], body: Some(RouteBody::Webhook), status: 201, response: Some("Webhook") },
+    RouteDoc { method: "get", path: "/webhooks", summary: "List the registered webhooks (admin only)", table: None, query: &[], body: None, status: 200, response: Some("Webhooks") },
+    RouteDoc { method: "delete", path: "/webhooks/{id}", summary: "Remove a webhook and its deliveries (admin only)", table: None, query: &[], body: None, status: 200, response: Some("Webhook") },
+    RouteDoc { method: "get", path: "/webhooks/{id}/deliveries", summary: "Read a webhook's delivery log, newest first, filtered by ?state= (admin only)", table: None, query: &["state", "limit", "offset"], body: None, status: 200, response: Some("WebhookDeliveries") },
+    RouteDoc { method: "post", path: "/webhooks/deliveries/{id}/retry", summary: "Send a dead webhook delivery again (admin only)", table: None, query: &[], body: None, status: 200, response: Some("WebhookDelivery") },
+    RouteDoc { method: "post", path: "/graphql", summary: "Run a GraphQL query or mutation on the tables", table: None, query: &[], body: Some(RouteBody::GraphQL), status: 200, response: Some("GraphQLResponse") },
+    RouteDoc { method: "get", path: "/cache", summary: "Read the response cache's size and hit counts (admin only)", table: None, query: &[], body: None, status: 200, response: Some("CacheStats") },
+    RouteDoc { method: "get", path: "/health", summary: "Check that the server is alive", table: None, query: &[], body: None, status: 200, response: Some("Health") },
+    RouteDoc { method: "get", path: "/ready", summary: "Check that the server can handle requests", table: None, query: &[], body: None, status: 200, response: Some("Readiness") },
+    RouteDoc { method: "get", path: "/openapi.json", summary: "Get this OpenAPI document", table: None, query: &[], body: None, status: 200, response: Some("Document") },
+    RouteDoc { method: "get", path: "/schema", summary: "Describe every table and field", table: None, query: &[], body: None, status: 200, response: Some("Document") },
+    RouteDoc { method: "get", path: "/schema/{table}", summary: "Get the JSON Schemas for a table's request bodies", table: None, query: &[], body: None, status: 200, response: Some("Document") },
+];
+
 /// Uses warp to respond to the client.
 /// 
-/// Status is the status code on success.
-pub(crate) fn respond<T: serde::Serialize>(result: Result<T, AppError>, status: warp::http::StatusCode) -> Result<impl Reply, Rejection> {
+/// Status is the status code on success, and format is the serialization the client accepts.
+pub(crate) fn respond<T: serde::Serialize>(result: Result<T, AppError>, status: warp::http::StatusCode, format: Format) -> Result<impl Reply, Rejection> {
     match result {
-        Ok(response) => 
-            Ok(warp::reply::with_status(warp::reply::json(&response), status)),
+        Ok(response) => {
+            let body = format.encode(&response).map_err(AppError::into_warp)?;
+            Ok(warp::reply::with_status(
+                warp::reply::with_header(body, warp::http::header::CONTENT_TYPE, format.mime()),
+                status
+            ))
+        },
         Err(err) => 
             Err(warp::reject::custom(err))
     }
 }
 
-/// Ensures that the request contains JSON within the size limit.
-pub(crate) fn with_json_body() -> impl Filter<Extract = (serde_json::Value,), Error = Rejection> + Clone {
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+/// Uses warp to respond to the client with a row and its `ETag`.
+pub(crate) fn respond_row(result: Result<Row, AppError>, status: warp::http::StatusCode, format: Format) -> Result<impl Reply, Rejection> {
+    let row = result.map_err(AppError::into_warp)?;
+    let etag = etag(&row);
+    Ok(warp::reply::with_header(
+        respond(Ok(row), status, format)?,
+        warp::http::header::ETAG,
+        etag
+    ))
+}
+
+/// Turns a row into the bytes sent for it by `respond_stream`.
+pub(crate) type RowEncoder = Box<dyn Fn(&Row) -> Result<Vec<u8>, AppError> + Send>;
+
+/// Uses warp to stream rows to the client as they are read from the database (see `db::stream_rows`).
+///
+/// The `prefix` is sent first, such as a CSV header, and then each encoded row.
+/// The first row is awaited before replying, so that errors such as an unreachable database still get an error response.
+/// Errors after that can only abort the response, since its status has already been sent.
+pub(crate) async fn respond_stream(
+    mut rows: mpsc::Receiver<Result<Row, AppError>>,
+    content_type: &'static str,
+    prefix: Vec<u8>,
+    encode: RowEncoder,
+) -> Result<warp::reply::Response, Rejection> {
+    let mut row = rows.recv().await.transpose().map_err(AppError::into_warp)?;
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut chunk = prefix;
+        loop {
+            if let Some(row) = &row {
+                match encode(row) {
+                    Ok(bytes) => chunk.extend(bytes),
+                    Err(err) => return abort_stream(sender, err),
+                }
+            }
+
+            // Waits for the client to read the previous chunk, and stops if it disconnected
+            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                return;
+            }
+            chunk = Vec::new();
+
+            row = match rows.recv().await {
+                Some(Ok(next)) => Some(next),
+                Some(Err(err)) => return abort_stream(sender, err),
+                None => return,
+            };
+        }
+    });
+
+    let mut response = warp::reply::Response::new(body);
+    response.headers_mut().insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::HeaderValue::from_static(content_type)
+    );
+    Ok(response)
+}
+
+/// Logs an error that happened while streaming, and cuts the response off so the client knows it is incomplete.
+fn abort_stream(sender: warp::hyper::body::Sender, err: AppError) {
+    let id = uuid::Uuid::new_v4().to_string();
+    eprintln!("error {} (while streaming): {}", id, err.message);
+    sender.abort();
+}
+
+/// The maximum size of a request body, in bytes.
+pub(crate) const MAX_BODY_SIZE: u64 = 1024 * 16;
+
+/// Ensures that the request contains JSON, MessagePack or CBOR within the size limit.
+/// 
+/// The body is decoded according to its `Content-Type`, which defaults to JSON.
+pub(crate) fn with_body() -> impl Filter<Extract = (serde_json::Value,), Error = Rejection> + Clone {
+    with_body_limit(MAX_BODY_SIZE)
+}
+
+/// Like `with_body`, but with a different size limit for routes that take larger bodies.
+pub(crate) fn with_body_limit(limit: u64) -> impl Filter<Extract = (serde_json::Value,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(limit)
+        .and(warp::header::optional::<String>(warp::http::header::CONTENT_TYPE.as_str()))
+        .and(warp::body::bytes())
+        .and_then(decode_body)
 }
@@ -0,0 +1,304 @@
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use rustract::field::FieldDesign;
+use rustract::table::TableDesign;
+use rustract::types::DataType;
+
+use crate::DB_DESIGN;
+use crate::AppError;
+use crate::ErrorType;
+use crate::db;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::dump::DUMP_INFO;
+
+/// The JSON Schema dialect used by the generated schemas.
+pub(crate) const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// The query parameters accepted by the JSON Schema route.
+#[derive(serde::Deserialize)]
+struct SchemaQuery {
+    variant: Option<String>,
+}
+
+// GET <domain>/schema
+/// A function that returns a warp route describing every table and field the server knows about.
+///
+/// This is meant for admin tooling that builds forms from the database design.
+pub(crate) fn get_schema() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("schema")
+        .and(warp::get())
+        .and(with_format())
+        .and_then(schema_success)
+}
+
+/// Replies with the description of every table.
+async fn schema_success(format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(gen_introspection()),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+// GET <domain>/schema/<table>
+/// A function that returns a warp route for a table's JSON Schemas.
+///
+/// `?variant=create` or `?variant=update` selects a single schema, otherwise both are listed under `$defs`.
+pub(crate) fn get_table_schema() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("schema" / String)
+        .and(warp::get())
+        .and(warp::query::<SchemaQuery>())
+        .and_then(table_schema_retrieve)
+        .and(with_format())
+        .and_then(table_schema_success)
+}
+
+/// Looks up the table and generates the requested schemas.
+async fn table_schema_retrieve(table: String, query: SchemaQuery) -> Result<Value, Rejection> {
+    let variant = match query.variant {
+        Some(variant) => Some(Variant::parse(&variant).ok_or_else(|| AppError::new(
+            ErrorType::BadRequest,
+            format!("unknown schema variant \"{}\" (expected create, update or row)", variant)
+        ))?),
+        None => None,
+    };
+    Ok(gen_json_schema(&table, variant)?)
+}
+
+/// Replies with the generated schema.
+async fn table_schema_success(schema: Value, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(schema),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+/// The shapes a table's rows take in requests and responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Variant {
+    /// A full row, as stored in the database.
+    Row,
+    /// A POST body, where required fields that are not generated must be included.
+    Create,
+    /// A PATCH body, where every field is optional and the id cannot be changed.
+    Update,
+}
+
+impl Variant {
+    /// Parses a variant from its lowercase name.
+    pub(crate) fn parse(name: &str) -> Option<Variant> {
+        match name {
+            "row" => Some(Variant::Row),
+            "create" => Some(Variant::Create),
+            "update" => Some(Variant::Update),
+            _ => None,
+        }
+    }
+
+    /// Returns the lowercase name of this variant, as used in queries.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Variant::Row => "row",
+            Variant::Create => "create",
+            Variant::Update => "update",
+        }
+    }
+
+    /// Returns the suffix used when naming this variant's schema, e.g. `UserCreate`.
+    pub(crate) fn suffix(&self) -> &'static str {
+        match self {
+            Variant::Row => "",
+            Variant::Create => "Create",
+            Variant::Update => "Update",
+        }
+    }
+}
+
+/// Converts a table name into the `PascalCase` name used for its schemas.
+pub(crate) fn schema_name(table: &str, variant: Variant) -> String {
+    let mut name: String = table
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect();
+    name += variant.suffix();
+    name
+}
+
+/// Returns the fields of a table sorted by name, so that generated documents are stable.
+pub(crate) fn sorted_fields(table: &TableDesign) -> Vec<&FieldDesign> {
+    let mut fields: Vec<&FieldDesign> = table.fields.values().collect();
+    fields.sort_by(|a, b| a.field_design_title.cmp(&b.field_design_title));
+    fields
+}
+
+/// Builds a JSON Schema (draft 2020-12) describing the values a field accepts.
+pub(crate) fn field_schema(field: &FieldDesign) -> Value {
+    let mut schema = Map::new();
+
+    let type_name = match field.datatype {
+        DataType::String => {
+            if let Some(bytes) = field.bytes {
+                schema.insert("maxLength".to_string(), json!(bytes));
+            }
+            Some("string")
+        },
+        DataType::Enum => {
+            if let Some(values) = &field.enum_set {
+                schema.insert("enum".to_string(), json!(values));
+            }
+            Some("string")
+        },
+        DataType::Unsigned => {
+            schema.insert("minimum".to_string(), json!(0));
+            Some("integer")
+        },
+        DataType::Signed => Some("integer"),
+        DataType::Float => Some("number"),
+        DataType::Boolean => Some("boolean"),
+        DataType::Json => None,
+        _ => Some("string"),
+    };
+
+    // Fields that are not required can be stored as NULL
+    if let Some(type_name) = type_name {
+        if field.required {
+            schema.insert("type".to_string(), json!(type_name));
+        } else {
+            schema.insert("type".to_string(), json!([type_name, "null"]));
+        }
+    }
+    if let Some(Value::Array(values)) = schema.get_mut("enum") {
+        if !field.required {
+            values.push(Value::Null);
+        }
+    }
+
+    if field.generated {
+        schema.insert("readOnly".to_string(), json!(true));
+    }
+
+    Value::Object(schema)
+}
+
+/// Builds a JSON Schema (draft 2020-12) for a table's rows.
+///
+/// The rules mirror `post_extract` and `patch_extract`, so the same bodies are accepted.
+pub(crate) fn table_schema(name: &str, table: &TableDesign, variant: Variant) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    let primary_key = db::primary_key(name);
+
+    for field in sorted_fields(table) {
+        let title = &field.field_design_title;
+        let is_required = match variant {
+            Variant::Row => field.required,
+            Variant::Create => field.required && !field.generated,
+            Variant::Update => false,
+        };
+
+        // The primary key is never updated by `patch_extract`
+        if variant == Variant::Update && *title == primary_key {
+            continue;
+        }
+
+        properties.insert(title.to_string(), field_schema(field));
+        if is_required {
+            required.push(title.to_string());
+        }
+    }
+
+    json!({
+        "title": schema_name(name, variant),
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// Generates a standalone JSON Schema document for a table.
+///
+/// If no variant is given, the create and update schemas are both listed under `$defs`.
+pub(crate) fn gen_json_schema(table: &str, variant: Option<Variant>) -> Result<Value, AppError> {
+    let design = DB_DESIGN.tables.get(table).ok_or_else(|| AppError::new(
+        ErrorType::NotFound,
+        format!("table {} does not exist", table)
+    ))?;
+
+    let mut document = match variant {
+        Some(variant) => table_schema(table, design, variant),
+        None => {
+            let mut defs = Map::new();
+            for variant in [Variant::Create, Variant::Update] {
+                defs.insert(schema_name(table, variant), table_schema(table, design, variant));
+            }
+            json!({ "$defs": defs })
+        },
+    };
+
+    let id = match variant {
+        Some(variant) => format!("/schema/{}?variant={}", table, variant.name()),
+        None => format!("/schema/{}", table),
+    };
+    if let Some(document) = document.as_object_mut() {
+        document.insert("$schema".to_string(), json!(DIALECT));
+        document.insert("$id".to_string(), json!(id));
+    }
+    Ok(document)
+}
+
+/// Describes every table and field parsed from the SQL dump.
+///
+/// The field details come from rustract, while defaults and key names come from `DUMP_INFO`.
+pub(crate) fn gen_introspection() -> Value {
+    let mut tables: Vec<(&String, &TableDesign)> = DB_DESIGN.tables.iter().collect();
+    tables.sort_by(|a, b| a.0.cmp(b.0));
+
+    let tables: Vec<Value> = tables
+        .into_iter()
+        .map(|(name, table)| {
+            let info = DUMP_INFO.get(name.as_str());
+            let fields: Vec<Value> = sorted_fields(table)
+                .into_iter()
+                .map(|field| {
+                    let column = info.and_then(|info| info.columns.get(&field.field_design_title));
+                    json!({
+                        "name": field.field_design_title,
+                        "datatype": format!("{:?}", field.datatype),
+                        "sql_type": column.map(|column| column.sql_type.clone()),
+                        "max_length": field.bytes,
+                        "enum_values": field.enum_set,
+                        "nullable": !field.required,
+                        "default": column.and_then(|column| column.default.clone()),
+                        "unique": field.unique,
+                        "primary_key": field.primary,
+                        "auto_increment": field.increment,
+                        "generated": field.generated,
+                        "schema": field_schema(field),
+                    })
+                })
+                .collect();
+
+            json!({
+                "name": name,
+                "primary_key": info.map(|info| info.primary_key.clone()).unwrap_or_default(),
+                "unique_keys": info.map(|info| info.unique_keys.clone()).unwrap_or_default(),
+                "fields": fields,
+            })
+        })
+        .collect();
+
+    json!({ "tables": tables })
+}
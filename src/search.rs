@@ -0,0 +1,335 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use sqlx::Row as SqlxRow;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+
+use crate::DB_DESIGN;
+use crate::env;
+use crate::ErrorType;
+use crate::AppError;
+use crate::Check;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond;
+use crate::format::Format;
+use crate::format::with_format;
+use crate::schema::sorted_fields;
+use crate::auth::Actor;
+use crate::auth::include_deleted;
+use crate::auth::with_actor;
+use crate::list::DEFAULT_LIMIT;
+use crate::list::MAX_LIMIT;
+use crate::list::parse_number;
+use rustract::types::DataType;
+
+/// The most words a search may have.
+const MAX_TERMS: usize = 10;
+
+/// About how many characters of context are kept on each side of a match in a snippet.
+const SNIPPET_CONTEXT: usize = 40;
+
+/// The column alias of each row's relevance score.
+const SCORE_COLUMN: &str = "_score";
+
+/// A parsed search request.
+pub(crate) struct SearchQuery {
+    /// The lowercase words of `q`, which match any word starting with them.
+    pub terms: Vec<String>,
+    pub include_deleted: bool,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+/// A row that matched a search.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct SearchHit {
+    /// The relevance reported by the full-text index, higher first.
+    pub score: f64,
+    pub row: Row,
+    /// For each searched column that matched, its value with the matched words wrapped in `<mark>`.
+    pub highlights: BTreeMap<String, String>,
+}
+
+// GET <domain>/user/search?q=ann
+/// A function that returns a warp route for searching users by the words in their text columns.
+///
+/// Every word of `q` must start a word in one of the searched columns, so `ann` finds "Anna" and "ann@example.com".
+/// Results are ranked by relevance and paged with `limit` and `offset`.
+pub(crate) fn search_user() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("user" / "search")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(search_extract)
+        .and_then(search_retrieve)
+        .and(with_format())
+        .and_then(search_success)
+}
+
+/// Splits `q` into words and checks the other parameters.
+async fn search_extract(query: HashMap<String, String>, actor: Option<Actor>) -> Result<SearchQuery, warp::reject::Rejection> {
+    let mut search = SearchQuery {
+        terms: Vec::new(),
+        include_deleted: include_deleted(query.get("include_deleted").map(String::as_str), &actor)?,
+        limit: DEFAULT_LIMIT,
+        offset: 0,
+    };
+
+    for (key, value) in query {
+        match key.as_str() {
+            "q" => search.terms = search_terms(&value),
+            "include_deleted" => {},
+            "limit" => search.limit = parse_number(&key, &value)?.min(MAX_LIMIT),
+            "offset" => search.offset = parse_number(&key, &value)?,
+            _ => Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("unknown query parameter {}: expected q, limit, offset or include_deleted", key),
+                field: Some(key),
+            })?,
+        }
+    }
+
+    if search.terms.is_empty() {
+        Err(AppError {
+            err_type: ErrorType::BadRequest,
+            message: "q should contain at least one word to search for".to_string(),
+            field: Some("q".to_string()),
+        })?
+    }
+    if search.terms.len() > MAX_TERMS {
+        Err(AppError {
+            err_type: ErrorType::BadRequest,
+            message: format!("q can have at most {} words, but has {}", MAX_TERMS, search.terms.len()),
+            field: Some("q".to_string()),
+        })?
+    }
+    Ok(search)
+}
+
+/// Splits a search into the words the full-text index uses, dropping punctuation such as `@` and `.`.
+fn search_terms(q: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+    for term in q.split(|c: char| !c.is_alphanumeric() && c != '_').filter(|term| !term.is_empty()) {
+        let term = term.to_lowercase();
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms
+}
+
+/// Returns the columns of a table that are searched: the ones listed in the `search` config,
+/// or every `varchar` field, sorted by name.
+///
+/// A `FULLTEXT` index on exactly these columns is needed, which a migration adds for the user table.
+pub(crate) fn search_columns(table: &str) -> Result<Vec<String>, AppError> {
+    let design = DB_DESIGN.tables.get(table).check()?;
+    let columns: Vec<String> = match env().and_then(|env| env.search.get(table)) {
+        Some(columns) => {
+            if let Some(unknown) = columns.iter().find(|column| !design.fields.contains_key(*column)) {
+                return Err(AppError::new(
+                    ErrorType::Internal,
+                    format!("err: search column {} is not a field of {} (check the search config)", unknown, table)
+                ));
+            }
+            columns.clone()
+        },
+        None => sorted_fields(design)
+            .into_iter()
+            .filter(|field| field.datatype == DataType::String)
+            .map(|field| field.field_design_title.clone())
+            .collect(),
+    };
+
+    if columns.is_empty() {
+        return Err(AppError::new(ErrorType::NotFound, format!("{} has no searchable columns", table)));
+    }
+    Ok(columns)
+}
+
+/// Uses the full-text index to find the matching users, best match first.
+async fn search_retrieve(search: SearchQuery) -> Result<Vec<SearchHit>, warp::reject::Rejection> {
+    let columns = search_columns("user")?;
+    let matches = format!(
+        "MATCH({}) AGAINST (? IN BOOLEAN MODE)",
+        columns.iter().map(|column| format!("`{}`", column)).collect::<Vec<String>>().join(", ")
+    );
+    // In boolean mode, `+word*` requires a word starting with `word`
+    let against = search.terms.iter().map(|term| format!("+{}*", term)).collect::<Vec<String>>().join(" ");
+    let sql = format!(
+        "SELECT {}, {} AS `{}` FROM `user` WHERE {}{} ORDER BY `{}` DESC, `{}` LIMIT ? OFFSET ?",
+        db::projection("user", None),
+        matches,
+        SCORE_COLUMN,
+        matches,
+        if search.include_deleted || !db::soft_deletes("user") { String::new() } else { format!(" AND `{}` IS NULL", db::SOFT_DELETE_COLUMN) },
+        SCORE_COLUMN,
+        db::primary_key("user")
+    );
+
+    let mut conn = db::acquire().await?;
+    let rows = sqlx::query(&sql)
+        .bind(&against)
+        .bind(&against)
+        .bind(search.limit)
+        .bind(search.offset)
+        .fetch_all(&mut conn)
+        .await
+        .map_err(AppError::from)?;
+
+    let mut hits = Vec::new();
+    for row in rows.iter() {
+        let score: f64 = row.try_get(SCORE_COLUMN).map_err(AppError::from)?;
+        let mut row = db::row_to_json(row)?;
+        row.remove(SCORE_COLUMN);
+        hits.push(SearchHit { score, row, highlights: BTreeMap::new() });
+    }
+    for hit in hits.iter_mut() {
+        for column in columns.iter() {
+            if let Some(snippet) = hit.row.get(column).and_then(|value| value.as_str()).and_then(|text| highlight(text, &search.terms)) {
+                hit.highlights.insert(column.to_string(), snippet);
+            }
+        }
+    }
+    Ok(hits)
+}
+
+/// Replies with the matching users and their highlights.
+async fn search_success(hits: Vec<SearchHit>, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(hits),
+        warp::http::StatusCode::OK,
+        format
+    )
+}
+
+/// Wraps the words of a text that start with one of the terms in `<mark>`, escaping the rest as HTML.
+///
+/// Long texts are cut down to the context around the first match. Returns `None` if nothing matched.
+fn highlight(text: &str, terms: &[String]) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets the same as in the original text
+    let lower = text.to_ascii_lowercase();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for term in terms {
+        let term = term.to_ascii_lowercase();
+        let mut from = 0;
+        while let Some(position) = lower[from..].find(&term) {
+            let start = from + position;
+            let starts_word = !lower[..start].chars().next_back().is_some_and(char::is_alphanumeric);
+            if starts_word {
+                // The whole word is marked, not just the prefix that was searched for
+                let end = start + lower[start..].find(|c: char| !c.is_alphanumeric()).unwrap_or(lower.len() - start);
+                ranges.push((start, end));
+            }
+            from = start + term.len();
+        }
+    }
+    if ranges.is_empty() {
+        return None;
+    }
+    ranges.sort();
+
+    // Overlapping matches are merged into one mark
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let first = merged[0];
+    let window_start = char_boundary(text, first.0.saturating_sub(SNIPPET_CONTEXT));
+    let window_end = char_boundary(text, (first.1 + SNIPPET_CONTEXT).min(text.len()));
+
+    let mut snippet = String::new();
+    if window_start > 0 {
+        snippet.push('…');
+    }
+    let mut position = window_start;
+    for (start, end) in merged {
+        if start >= window_end {
+            break;
+        }
+        let end = end.min(window_end);
+        snippet.push_str(&escape_html(&text[position..start]));
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape_html(&text[start..end]));
+        snippet.push_str("</mark>");
+        position = end;
+    }
+    snippet.push_str(&escape_html(&text[position..window_end]));
+    if window_end < text.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+/// Moves a byte offset back to the start of the character it is in.
+fn char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Escapes text for HTML, so that only the `<mark>` tags are markup.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|term| term.to_string()).collect()
+    }
+
+    #[test]
+    fn terms_drop_punctuation_and_repeats() {
+        assert_eq!(search_terms("ann@example.com"), terms(&["ann", "example", "com"]));
+        assert_eq!(search_terms("  Ann  ANN ann_b "), terms(&["ann", "ann_b"]));
+        assert_eq!(search_terms("Émile"), terms(&["émile"]));
+        assert!(search_terms(" @.- ").is_empty());
+    }
+
+    #[test]
+    fn highlight_marks_whole_words_starting_with_a_term() {
+        assert_eq!(highlight("Anna Smith", &terms(&["ann"])).as_deref(), Some("<mark>Anna</mark> Smith"));
+        assert_eq!(
+            highlight("ann@example.com", &terms(&["ann", "exa"])).as_deref(),
+            Some("<mark>ann</mark>@<mark>example</mark>.com")
+        );
+        // Only the starts of words match
+        assert_eq!(highlight("Joanna", &terms(&["ann"])), None);
+        assert_eq!(highlight("Joanna ann", &terms(&["ann"])).as_deref(), Some("Joanna <mark>ann</mark>"));
+    }
+
+    #[test]
+    fn highlight_merges_overlapping_matches() {
+        assert_eq!(highlight("Annabel", &terms(&["ann", "anna"])).as_deref(), Some("<mark>Annabel</mark>"));
+    }
+
+    #[test]
+    fn highlight_escapes_html() {
+        assert_eq!(
+            highlight("<b>Ann</b> & \"co\"", &terms(&["ann"])).as_deref(),
+            Some("&lt;b&gt;<mark>Ann</mark>&lt;/b&gt; &amp; &quot;co&quot;")
+        );
+    }
+
+    #[test]
+    fn highlight_cuts_long_texts_around_the_first_match() {
+        let text = format!("{} ann {}", "x".repeat(100), "y".repeat(100));
+        let snippet = highlight(&text, &terms(&["ann"])).unwrap();
+        assert_eq!(snippet, format!("…{} <mark>ann</mark> {}…", "x".repeat(SNIPPET_CONTEXT - 1), "y".repeat(SNIPPET_CONTEXT - 1)));
+
+        // The window never splits a character
+        let text = format!("{} ann", "é".repeat(50));
+        let snippet = highlight(&text, &terms(&["ann"])).unwrap();
+        assert!(snippet.starts_with('…') && snippet.ends_with("<mark>ann</mark>"));
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use serde_json::Value;
+use sqlx::MySqlConnection;
+use sqlx::Row as SqlxRow;
+use sqlx::mysql::MySqlRow;
+use warp::Rejection;
+use warp::Reply;
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::DB_DESIGN;
+use crate::ErrorType;
+use crate::AppError;
+use crate::db;
+use crate::db::Row;
+use crate::routes::respond;
+use crate::routes::with_body;
+use crate::format::Format;
+use crate::format::check_accept;
+use crate::format::with_format;
+use crate::auth::Actor;
+use crate::auth::actor_name;
+use crate::auth::require_admin;
+use crate::auth::with_actor;
+use crate::audit::Action;
+use crate::audit::AuditContext;
+use crate::delivery;
+use crate::list::DEFAULT_LIMIT;
+use crate::list::MAX_LIMIT;
+use crate::list::parse_number;
+
+/// The table webhook subscriptions are kept in, created by a migration.
+const WEBHOOK_TABLE: &str = "webhooks";
+
+/// The table each event sent to a webhook is queued in, see `delivery::run`.
+pub(crate) const DELIVERY_TABLE: &str = "webhook_deliveries";
+
+/// The shortest secret an admin may choose, since it is all that proves a delivery is genuine.
+const MIN_SECRET_LENGTH: usize = 16;
+
+/// The states a delivery goes through: `pending` until it succeeds (`delivered`) or runs out of attempts (`dead`).
+pub(crate) const DELIVERY_STATES: [&str; 3] = ["pending", "delivered", "dead"];
+
+/// The body of a webhook registration.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhookRequest {
+    /// The `http` or `https` url events are posted to.
+    url: String,
+    /// The table whose changes are sent, or every table if missing.
+    table: Option<String>,
+    /// The actions that are sent, or every action if empty.
+    #[serde(default)]
+    actions: Vec<String>,
+    /// The key deliveries are signed with, which is generated if missing.
+    secret: Option<String>,
+}
+
+/// A registered webhook.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Webhook {
+    pub id: u32,
+    pub url: String,
+    pub table: Option<String>,
+    pub actions: Vec<String>,
+    /// Only included in the reply to the registration, so that it is not shown again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub created_by: String,
+    pub created_at: Option<String>,
+}
+
+impl Webhook {
+    /// Reads a webhook from a row of the webhook table, leaving out its secret.
+    fn from_row(row: &MySqlRow) -> Result<Webhook, AppError> {
+        let actions: String = row.try_get("actions")?;
+        Ok(Webhook {
+            id: row.try_get("id")?,
+            url: row.try_get("url")?,
+            table: row.try_get("table_name")?,
+            actions: actions.split(',').map(str::to_string).collect(),
+            secret: None,
+            created_by: row.try_get("created_by")?,
+            created_at: row
+                .try_get::<Option<chrono::DateTime<chrono::Utc>>, _>("created_at")?
+                .map(|time| time.to_rfc3339()),
+        })
+    }
+}
+
+/// Queues a change to be sent to every webhook that subscribed to it.
+///
+/// Like `audit::record`, this should use the same transaction as the change, so that only committed changes are sent.
+/// The deliveries are made by `delivery::run`, which `AuditContext::publish` wakes once they are committed.
+pub(crate) async fn enqueue(
+    conn: &mut MySqlConnection,
+    context: &AuditContext,
+    table: &str,
+    id: u32,
+    action: Action,
+    before: Option<&Row>,
+    after: Option<&Row>,
+) -> Result<(), AppError> {
+    let sql = format!(
+        "SELECT `id` FROM `{}` WHERE (`table_name` IS NULL OR `table_name` = ?) AND FIND_IN_SET(?, `actions`) > 0",
+        WEBHOOK_TABLE
+    );
+    let webhooks: Vec<u32> = sqlx::query_scalar(&sql)
+        .bind(table)
+        .bind(action.name())
+        .fetch_all(&mut *conn)
+        .await?;
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    // Hard deletes have no row afterwards, so the deleted row is sent instead
+    let payload = serde_json::to_string(&serde_json::json!({
+        "event_id": uuid::Uuid::new_v4().to_string(),
+        "table": table,
+        "id": id,
+        "action": action.name(),
+        "actor": actor_name(&context.actor),
+        "request_id": context.request_id,
+        "occurred_at": chrono::Utc::now().to_rfc3339(),
+        "row": after.or(before),
+    }))?;
+    let sql = format!(
+        "INSERT INTO `{}` (`webhook_id`, `action`, `payload`, `next_attempt_at`) VALUES (?, ?, ?, UTC_TIMESTAMP())",
+        DELIVERY_TABLE
+    );
+    for webhook in webhooks {
+        sqlx::query(&sql)
+            .bind(webhook)
+            .bind(action.name())
+            .bind(&payload)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+// POST <domain>/webhooks
+/// A function that returns a warp route for registering a webhook (admin only).
+///
+/// The reply includes the signing secret, which is not shown again.
+pub(crate) fn post_webhook() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("webhooks")
+        .and(warp::post())
+        .and(check_accept())
+        .and(with_body())
+        .and(with_actor())
+        .and_then(webhook_insert)
+        .and(with_format())
+        .and_then(webhook_success)
+}
+
+/// Validates the registration and inserts the webhook.
+async fn webhook_insert(body: Value, actor: Option<Actor>) -> Result<Webhook, warp::reject::Rejection> {
+    require_admin(&actor)?;
+    let req: WebhookRequest = serde_json::from_value(body).map_err(|err| AppError::new(
+        ErrorType::BadRequest,
+        format!("failed to parse webhook (err: {})", err)
+    ))?;
+
+    if !req.url.starts_with("http://") && !req.url.starts_with("https://") {
+        Err(invalid_field("url", "url should start with http:// or https://".to_string()))?
+    }
+    if let Some(table) = &req.table {
+        if !DB_DESIGN.tables.contains_key(table) {
+            Err(invalid_field("table", format!("cannot subscribe to table {}, since it does not exist", table)))?
+        }
+    }
+    let mut actions = Vec::new();
+    for action in Action::ALL {
+        if req.actions.is_empty() || req.actions.iter().any(|name| name == action.name()) {
+            actions.push(action.name().to_string());
+        }
+    }
+    if let Some(unknown) = req.actions.iter().find(|name| !actions.contains(name)) {
+        Err(invalid_field("actions", format!("unknown action {}: expected create, update, delete or restore", unknown)))?
+    }
+    let secret = match req.secret {
+        Some(secret) if secret.len() < MIN_SECRET_LENGTH => Err(invalid_field(
+            "secret",
+            format!("secret should be at least {} characters long", MIN_SECRET_LENGTH)
+        ))?,
+        Some(secret) => secret,
+        None => format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple()),
+    };
+
+    let mut conn = db::acquire().await?;
+    let sql = format!(
+        "INSERT INTO `{}` (`url`, `secret`, `table_name`, `actions`, `created_by`) VALUES (?, ?, ?, ?, ?)",
+        WEBHOOK_TABLE
+    );
+    let id = sqlx::query(&sql)
+        .bind(&req.url)
+        .bind(&secret)
+        .bind(&req.table)
+        .bind(actions.join(","))
+        .bind(actor_name(&actor))
+        .execute(&mut conn)
+        .await
+        .map_err(AppError::from)?
+        .last_insert_id();
+
+    let id = u32::try_from(id).map_err(|_| AppError::new(
+        ErrorType::Internal,
+        format!("err: inserted id {} does not fit in a u32", id)
+    ))?;
+    let mut webhook = fetch_webhook(&mut conn, id).await?;
+    webhook.secret = Some(secret);
+    Ok(webhook)
+}
+
+/// Replies with the new webhook and its secret.
+async fn webhook_success(webhook: Webhook, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(webhook),
+        StatusCode::CREATED,
+        format
+    )
+}
+
+// GET <domain>/webhooks
+/// A function that returns a warp route for listing the registered webhooks (admin only).
+pub(crate) fn list_webhooks() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("webhooks")
+        .and(warp::get())
+        .and(with_actor())
+        .and_then(webhooks_retrieve)
+        .and(with_format())
+        .and_then(webhooks_success)
+}
+
+/// Reads every webhook, oldest first.
+async fn webhooks_retrieve(actor: Option<Actor>) -> Result<Vec<Webhook>, warp::reject::Rejection> {
+    require_admin(&actor)?;
+    let mut conn = db::acquire().await?;
+    let sql = format!("SELECT * FROM `{}` ORDER BY `id`", WEBHOOK_TABLE);
+    let rows = sqlx::query(&sql)
+        .fetch_all(&mut conn)
+        .await
+        .map_err(AppError::from)?;
+    Ok(rows.iter().map(Webhook::from_row).collect::<Result<_, _>>()?)
+}
+
+/// Replies with the webhooks.
+async fn webhooks_success(webhooks: Vec<Webhook>, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(webhooks),
+        StatusCode::OK,
+        format
+    )
+}
+
+// DELETE <domain>/webhooks/#
+/// A function that returns a warp route for removing a webhook and its deliveries (admin only).
+pub(crate) fn delete_webhook() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("webhooks" / u32)
+        .and(warp::delete())
+        .and(check_accept())
+        .and(with_actor())
+        .and_then(webhook_delete)
+        .and(with_format())
+        .and_then(webhook_delete_success)
+}
+
+/// Deletes the webhook, passing it on so that it can be included in the reply.
+async fn webhook_delete(id: u32, actor: Option<Actor>) -> Result<Webhook, warp::reject::Rejection> {
+    require_admin(&actor)?;
+    let mut conn = db::acquire().await?;
+    let webhook = fetch_webhook(&mut conn, id).await?;
+    let sql = format!("DELETE FROM `{}` WHERE `id` = ?", WEBHOOK_TABLE);
+    sqlx::query(&sql)
+        .bind(id)
+        .execute(&mut conn)
+        .await
+        .map_err(AppError::from)?;
+    Ok(webhook)
+}
+
+/// Replies with the deleted webhook.
+async fn webhook_delete_success(webhook: Webhook, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(webhook),
+        StatusCode::OK,
+        format
+    )
+}
+
+// GET <domain>/webhooks/#/deliveries?state=dead
+/// A function that returns a warp route for reading a webhook's delivery log, newest first (admin only).
+///
+/// It can be narrowed with `state`, and paged with `limit` and `offset`.
+pub(crate) fn get_deliveries() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("webhooks" / u32 / "deliveries")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_actor())
+        .and_then(deliveries_retrieve)
+        .and(with_format())
+        .and_then(deliveries_success)
+}
+
+/// Uses the query to make an SQL SELECT query on the deliveries.
+async fn deliveries_retrieve(id: u32, query: HashMap<String, String>, actor: Option<Actor>) -> Result<Vec<Row>, warp::reject::Rejection> {
+    require_admin(&actor)?;
+    let mut state = None;
+    let mut limit = DEFAULT_LIMIT;
+    let mut offset = 0;
+    for (key, value) in query {
+        match key.as_str() {
+            "state" if DELIVERY_STATES.contains(&value.as_str()) => state = Some(value),
+            "state" => Err(invalid_field("state", format!("unknown state {}: expected pending, delivered or dead", value)))?,
+            "limit" => limit = parse_number(&key, &value)?.min(MAX_LIMIT),
+            "offset" => offset = parse_number(&key, &value)?,
+            _ => Err(AppError {
+                err_type: ErrorType::BadRequest,
+                message: format!("unknown query parameter {}: expected state, limit or offset", key),
+                field: Some(key),
+            })?,
+        }
+    }
+
+    let mut conn = db::acquire().await?;
+    fetch_webhook(&mut conn, id).await?;
+    let sql = format!(
+        "SELECT * FROM `{}` WHERE `webhook_id` = ?{} ORDER BY `id` DESC LIMIT ? OFFSET ?",
+        DELIVERY_TABLE,
+        if state.is_some() { " AND `state` = ?" } else { "" }
+    );
+    let mut sql_query = sqlx::query(&sql).bind(id);
+    if let Some(state) = state {
+        sql_query = sql_query.bind(state);
+    }
+    let rows = sql_query
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&mut conn)
+        .await
+        .map_err(AppError::from)?;
+
+    Ok(rows.iter().map(delivery_to_json).collect::<Result<_, _>>()?)
+}
+
+/// Replies with the deliveries.
+async fn deliveries_success(deliveries: Vec<Row>, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(deliveries),
+        StatusCode::OK,
+        format
+    )
+}
+
+// POST <domain>/webhooks/deliveries/#/retry
+/// A function that returns a warp route for sending a dead delivery again (admin only).
+///
+/// The delivery gets a fresh set of attempts, starting right away.
+pub(crate) fn retry_delivery() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("webhooks" / "deliveries" / u64 / "retry")
+        .and(warp::post())
+        .and(check_accept())
+        .and(with_actor())
+        .and_then(retry_insert)
+        .and(with_format())
+        .and_then(retry_success)
+}
+
+/// Moves a dead delivery back to `pending`, or errors with `409 Conflict` if it is not dead.
+async fn retry_insert(id: u64, actor: Option<Actor>) -> Result<Row, warp::reject::Rejection> {
+    require_admin(&actor)?;
+    let mut conn = db::acquire().await?;
+    let sql = format!(
+        "UPDATE `{}` SET `state` = 'pending', `attempts` = 0, `next_attempt_at` = UTC_TIMESTAMP() WHERE `id` = ? AND `state` = 'dead'",
+        DELIVERY_TABLE
+    );
+    let retried = sqlx::query(&sql)
+        .bind(id)
+        .execute(&mut conn)
+        .await
+        .map_err(AppError::from)?
+        .rows_affected() > 0;
+
+    let sql = format!("SELECT * FROM `{}` WHERE `id` = ?", DELIVERY_TABLE);
+    let delivery = sqlx::query(&sql)
+        .bind(id)
+        .fetch_optional(&mut conn)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| AppError::new(ErrorType::NotFound, format!("Not Found: delivery {} does not exist", id)))?;
+    if !retried {
+        Err(AppError::new(ErrorType::Conflict, format!("delivery {} cannot be retried, since it is not dead", id)))?
+    }
+
+    delivery::wake();
+    Ok(delivery_to_json(&delivery)?)
+}
+
+/// Replies with the delivery, which is pending again.
+async fn retry_success(delivery: Row, format: Format) -> Result<impl Reply, Rejection> {
+    respond(
+        Ok(delivery),
+        StatusCode::OK,
+        format
+    )
+}
+
+/// Reads a webhook by id, or errors with `404 Not Found`.
+async fn fetch_webhook(conn: &mut MySqlConnection, id: u32) -> Result<Webhook, AppError> {
+    let sql = format!("SELECT * FROM `{}` WHERE `id` = ?", WEBHOOK_TABLE);
+    let row = sqlx::query(&sql)
+        .bind(id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or_else(|| AppError::new(ErrorType::NotFound, format!("Not Found: webhook {} does not exist", id)))?;
+    Webhook::from_row(&row)
+}
+
+/// Converts a delivery row to JSON, parsing the payload that is stored as text.
+fn delivery_to_json(row: &MySqlRow) -> Result<Row, AppError> {
+    let mut delivery = db::row_to_json(row)?;
+    if let Some(Value::String(text)) = delivery.get("payload") {
+        let payload = serde_json::from_str(text)?;
+        delivery.insert("payload".to_string(), payload);
+    }
+    Ok(delivery)
+}
+
+/// Describes an invalid field of a request.
+fn invalid_field(field: &str, message: String) -> AppError {
+    AppError {
+        err_type: ErrorType::BadRequest,
+        message,
+        field: Some(field.to_string()),
+    }
+}